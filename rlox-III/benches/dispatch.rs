@@ -0,0 +1,30 @@
+//! Times the interpreter loop on a few small programs.
+//! Run with `cargo bench -p rlox-iii`.
+
+use rlox_iii::vm::Vm;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+const SCRIPTS: &[(&str, &str)] = &[
+    ("fib", include_str!("fib.lox")),
+    ("loop", include_str!("loop.lox")),
+    ("method_call", include_str!("method_call.lox")),
+];
+
+fn main() {
+    for (name, source) in SCRIPTS {
+        let mut best = Duration::MAX;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            Vm::interpret(source.to_string()).expect("benchmark script failed");
+            best = best.min(start.elapsed());
+        }
+        println!(
+            "{:<12} {:>8.2} ms (best of {})",
+            name,
+            best.as_secs_f64() * 1000.0,
+            RUNS
+        );
+    }
+}
@@ -1,16 +1,36 @@
+use crate::object::{ObjFunction, ObjKind};
 use crate::value::Value;
 
-#[derive(Debug)]
+use std::io::{self, Write};
+
+/// How many column runs a chunk remembers the start of while it's written.
+const COLUMN_TAIL: usize = 4;
+
+#[derive(Debug, Default)]
 pub struct Chunk {
     count: usize,
     #[allow(dead_code)]
     capacity: usize,
     // FIXME: Vec already has all of features above...
     pub code: Vec<u8>,
-    pub lines: Vec<i32>,
+    /// One run per source line change, ordered by `offset`.
+    pub lines: Vec<LineRun>,
+    /// Columns as a byte stream of runs: a run length byte followed by the
+    /// column as a LEB128 varint. See [Chunk::get_column].
+    pub columns: Vec<u8>,
+    /// Index in `columns` of the last few runs' length bytes, and their
+    /// columns, newest last. Lets [Chunk::truncate] step back over them.
+    column_tail: Vec<(usize, i32)>,
     pub constants: Vec<Value>,
 }
 
+/// Every byte from `offset` up to the next run's offset is on `line`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRun {
+    pub offset: usize,
+    pub line: i32,
+}
+
 impl Chunk {
     /// Used as void initChunk(Chunk* chunk);
     pub fn new() -> Self {
@@ -19,84 +39,404 @@ impl Chunk {
             capacity: 0,
             code: Vec::new(),
             lines: Vec::new(),
+            columns: Vec::new(),
+            column_tail: Vec::new(),
             constants: Vec::new(),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: i32) {
+    /// Rebuilds a finished chunk, as loaded from a .loxc file.
+    pub fn from_parts(
+        code: Vec<u8>,
+        lines: Vec<LineRun>,
+        columns: Vec<u8>,
+        constants: Vec<Value>,
+    ) -> Self {
+        Self {
+            count: code.len(),
+            capacity: code.len(),
+            code,
+            lines,
+            columns,
+            column_tail: Vec::new(),
+            constants,
+        }
+    }
+
+    pub fn write(&mut self, byte: u8, line: i32, column: i32) {
+        let offset = self.code.len();
         self.code.push(byte);
-        self.lines.push(line);
-        assert_eq!(self.code.len(), self.lines.len());
         self.count = self.code.len();
+
+        if self.lines.last().map(|run| run.line) != Some(line) {
+            self.lines.push(LineRun { offset, line });
+        }
+
+        match self.column_tail.last() {
+            Some(&(run, last)) if last == column && self.columns[run] < u8::MAX => {
+                self.columns[run] += 1;
+            }
+            _ => {
+                if self.column_tail.len() == COLUMN_TAIL {
+                    self.column_tail.remove(0);
+                }
+                self.column_tail.push((self.columns.len(), column));
+                self.columns.push(1);
+                let mut rest = column as u32;
+                loop {
+                    let byte = (rest & 0x7f) as u8;
+                    rest >>= 7;
+                    if rest == 0 {
+                        self.columns.push(byte);
+                        break;
+                    }
+                    self.columns.push(byte | 0x80);
+                }
+            }
+        }
+    }
+
+    /// Drops the code from `len` on, with its lines and columns, so the
+    /// compiler can replace the last few instructions with a fused one.
+    pub fn truncate(&mut self, len: usize) {
+        let mut dropped = self.code.len() - len;
+        self.code.truncate(len);
+        self.count = len;
+
+        while self.lines.last().is_some_and(|run| run.offset >= len) {
+            self.lines.pop();
+        }
+
+        while dropped > 0 {
+            if self.column_tail.is_empty() {
+                self.column_tail = self.last_column_runs();
+            }
+            let (run, _) = *self.column_tail.last().unwrap();
+            let length = self.columns[run] as usize;
+            if length > dropped {
+                self.columns[run] -= dropped as u8;
+                break;
+            }
+            dropped -= length;
+            self.columns.truncate(run);
+            self.column_tail.pop();
+        }
+    }
+
+    /// Finds where the last few column runs start by decoding from the top.
+    fn last_column_runs(&self) -> Vec<(usize, i32)> {
+        let mut tail = Vec::new();
+        let mut run = 0;
+        for (_, column) in self.column_runs() {
+            if tail.len() == COLUMN_TAIL {
+                tail.remove(0);
+            }
+            tail.push((run, column));
+            run += 1;
+            while self.columns[run] & 0x80 != 0 {
+                run += 1;
+            }
+            run += 1;
+        }
+        tail
+    }
+
+    /// The source line of the instruction byte at `offset`.
+    pub fn get_line(&self, offset: usize) -> i32 {
+        let index = self.lines.partition_point(|run| run.offset <= offset);
+        self.lines[index - 1].line
+    }
+
+    /// The 1-based source column of the token that emitted the byte at `offset`.
+    /// Walks the column runs from the start, so keep it off hot paths.
+    pub fn get_column(&self, offset: usize) -> i32 {
+        let mut covered = 0;
+        for (length, column) in self.column_runs() {
+            covered += length;
+            if offset < covered {
+                return column;
+            }
+        }
+        0
     }
 
-    pub fn add_constant(&mut self, value: f64) -> usize {
-        self.constants.push(Value::new(value));
+    /// Decodes `columns` into (run length, column) pairs, in code order.
+    pub fn column_runs(&self) -> impl Iterator<Item = (usize, i32)> + '_ {
+        let mut bytes = self.columns.iter();
+        std::iter::from_fn(move || {
+            let length = *bytes.next()?;
+            let mut column = 0;
+            let mut shift = 0;
+            for &byte in bytes.by_ref() {
+                column |= ((byte & 0x7f) as u32) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            Some((length as usize, column as i32))
+        })
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
         self.constants.len() - 1
     }
 }
 
 impl Chunk {
-    #[cfg(debug_assertions)]
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+    /// The whole chunk as text, one instruction per line.
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut out = Vec::new();
+        self.disassemble_to(name, &mut out)
+            .expect("writing to a Vec can't fail");
+        String::from_utf8(out).expect("disassembly is UTF-8")
+    }
+
+    pub fn disassemble_to(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {} ==", name)?;
 
         let mut offset = 0;
         while offset < self.count {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, out)?;
         }
+        Ok(())
     }
 
-    #[cfg(debug_assertions)]
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+    /// Writes the instruction at `offset` and returns the offset of the next one.
+    pub fn disassemble_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
         use crate::OpCode::*;
-        print!("{:04} ", offset);
+        write!(out, "{:04} ", offset)?;
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+        let line = self.get_line(offset);
+        if offset > 0 && line == self.get_line(offset - 1) {
+            write!(out, "   | ")?;
         } else {
-            print!("{:4} ", self.lines[offset]);
+            write!(out, "{:4} ", line)?;
         }
 
         let instruction = self.code[offset];
         match instruction.into() {
-            OP_CONSTANT => instructions::constant("OP_CONSTANT", self, offset),
-            OP_ADD => instructions::simple("OP_ADD", offset),
-            OP_SUBTRACT => instructions::simple("OP_SUBTRACT", offset),
-            OP_MULTIPLY => instructions::simple("OP_MULTIPLY", offset),
-            OP_DIVIDE => instructions::simple("OP_DIVIDE", offset),
-            OP_NEGATE => instructions::simple("OP_NEGATE", offset),
-            OP_RETURN => instructions::simple("OP_RETURN", offset),
+            OP_CONSTANT => instructions::constant("OP_CONSTANT", self, offset, out),
+            OP_CONSTANT_LONG => instructions::constant_long("OP_CONSTANT_LONG", self, offset, out),
+            OP_NIL => instructions::simple("OP_NIL", offset, out),
+            OP_TRUE => instructions::simple("OP_TRUE", offset, out),
+            OP_FALSE => instructions::simple("OP_FALSE", offset, out),
+            OP_POP => instructions::simple("OP_POP", offset, out),
+            OP_GET_LOCAL => instructions::byte("OP_GET_LOCAL", self, offset, out),
+            OP_SET_LOCAL => instructions::byte("OP_SET_LOCAL", self, offset, out),
+            OP_GET_GLOBAL => instructions::constant("OP_GET_GLOBAL", self, offset, out),
+            OP_GET_GLOBAL_LONG => {
+                instructions::constant_long("OP_GET_GLOBAL_LONG", self, offset, out)
+            }
+            OP_DEFINE_GLOBAL => instructions::constant("OP_DEFINE_GLOBAL", self, offset, out),
+            OP_DEFINE_GLOBAL_LONG => {
+                instructions::constant_long("OP_DEFINE_GLOBAL_LONG", self, offset, out)
+            }
+            OP_SET_GLOBAL => instructions::constant("OP_SET_GLOBAL", self, offset, out),
+            OP_SET_GLOBAL_LONG => {
+                instructions::constant_long("OP_SET_GLOBAL_LONG", self, offset, out)
+            }
+            OP_GET_UPVALUE => instructions::byte("OP_GET_UPVALUE", self, offset, out),
+            OP_SET_UPVALUE => instructions::byte("OP_SET_UPVALUE", self, offset, out),
+            OP_GET_PROPERTY => instructions::constant("OP_GET_PROPERTY", self, offset, out),
+            OP_GET_PROPERTY_LONG => {
+                instructions::constant_long("OP_GET_PROPERTY_LONG", self, offset, out)
+            }
+            OP_SET_PROPERTY => instructions::constant("OP_SET_PROPERTY", self, offset, out),
+            OP_SET_PROPERTY_LONG => {
+                instructions::constant_long("OP_SET_PROPERTY_LONG", self, offset, out)
+            }
+            OP_GET_SUPER => instructions::constant("OP_GET_SUPER", self, offset, out),
+            OP_GET_SUPER_LONG => {
+                instructions::constant_long("OP_GET_SUPER_LONG", self, offset, out)
+            }
+            OP_EQUAL => instructions::simple("OP_EQUAL", offset, out),
+            OP_GREATER => instructions::simple("OP_GREATER", offset, out),
+            OP_LESS => instructions::simple("OP_LESS", offset, out),
+            OP_ADD => instructions::simple("OP_ADD", offset, out),
+            OP_SUBTRACT => instructions::simple("OP_SUBTRACT", offset, out),
+            OP_MULTIPLY => instructions::simple("OP_MULTIPLY", offset, out),
+            OP_DIVIDE => instructions::simple("OP_DIVIDE", offset, out),
+            OP_NOT => instructions::simple("OP_NOT", offset, out),
+            OP_NEGATE => instructions::simple("OP_NEGATE", offset, out),
+            OP_PRINT => instructions::simple("OP_PRINT", offset, out),
+            OP_JUMP => instructions::jump("OP_JUMP", 1, self, offset, out),
+            OP_JUMP_IF_FALSE => instructions::jump("OP_JUMP_IF_FALSE", 1, self, offset, out),
+            OP_LOOP => instructions::jump("OP_LOOP", -1, self, offset, out),
+            OP_CALL => instructions::byte("OP_CALL", self, offset, out),
+            OP_INVOKE => instructions::invoke("OP_INVOKE", 1, self, offset, out),
+            OP_INVOKE_LONG => instructions::invoke("OP_INVOKE_LONG", 3, self, offset, out),
+            OP_SUPER_INVOKE => instructions::invoke("OP_SUPER_INVOKE", 1, self, offset, out),
+            OP_SUPER_INVOKE_LONG => {
+                instructions::invoke("OP_SUPER_INVOKE_LONG", 3, self, offset, out)
+            }
+            OP_CLOSURE => instructions::closure("OP_CLOSURE", 1, self, offset, out),
+            OP_CLOSURE_LONG => instructions::closure("OP_CLOSURE_LONG", 3, self, offset, out),
+            OP_CLOSE_UPVALUE => instructions::simple("OP_CLOSE_UPVALUE", offset, out),
+            OP_RETURN => instructions::simple("OP_RETURN", offset, out),
+            OP_CLASS => instructions::constant("OP_CLASS", self, offset, out),
+            OP_CLASS_LONG => instructions::constant_long("OP_CLASS_LONG", self, offset, out),
+            OP_INHERIT => instructions::simple("OP_INHERIT", offset, out),
+            OP_METHOD => instructions::constant("OP_METHOD", self, offset, out),
+            OP_METHOD_LONG => instructions::constant_long("OP_METHOD_LONG", self, offset, out),
+            OP_GET_LOCAL_0 => instructions::simple("OP_GET_LOCAL_0", offset, out),
+            OP_GET_LOCAL_1 => instructions::simple("OP_GET_LOCAL_1", offset, out),
+            OP_GET_LOCAL_2 => instructions::simple("OP_GET_LOCAL_2", offset, out),
+            OP_GET_LOCAL_3 => instructions::simple("OP_GET_LOCAL_3", offset, out),
+            OP_ADD_CONSTANT => instructions::constant("OP_ADD_CONSTANT", self, offset, out),
+            OP_LESS_JUMP_IF_FALSE => {
+                instructions::jump("OP_LESS_JUMP_IF_FALSE", 1, self, offset, out)
+            }
+            OP_INCREMENT_LOCAL => instructions::byte("OP_INCREMENT_LOCAL", self, offset, out),
             _ => {
-                println!("Unknown opcode: {}", &instruction);
-                offset + 1
+                writeln!(out, "Unknown opcode: {}", instruction)?;
+                Ok(offset + 1)
             }
         }
     }
+}
 
-    #[cfg(not(debug_assertions))]
-    pub fn disassemble(&self, _name: &str) {}
+/// Disassembles `function` and its constant table, then every function nested
+/// in its constants.
+pub fn disassemble_function(function: &ObjFunction, out: &mut dyn Write) -> io::Result<()> {
+    let name = match function.name {
+        Some(name) => name.to_string(),
+        None => "<script>".to_string(),
+    };
+    function.chunk.disassemble_to(&name, out)?;
+    if !function.chunk.constants.is_empty() {
+        writeln!(out, "-- constants --")?;
+        for (index, constant) in function.chunk.constants.iter().enumerate() {
+            writeln!(out, "{:4} {}", index, constant)?;
+        }
+    }
 
-    #[cfg(not(debug_assertions))]
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        0
+    for constant in function.chunk.constants.iter() {
+        if constant.is_obj() {
+            if let ObjKind::Function(nested) = &constant.as_obj().kind {
+                writeln!(out)?;
+                disassemble_function(nested, out)?;
+            }
+        }
     }
+    Ok(())
 }
 
-#[cfg(debug_assertions)]
 mod instructions {
     use super::*;
 
-    pub fn simple(name: &str, offset: usize) -> usize {
-        println!("{}", name);
-        offset + 1
+    pub fn simple(name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        writeln!(out, "{}", name)?;
+        Ok(offset + 1)
     }
 
-    pub fn constant(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    pub fn byte(
+        name: &str,
+        chunk: &Chunk,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let slot = chunk.code[offset + 1];
+        writeln!(out, "{:<16} {:4}", name, slot)?;
+        Ok(offset + 2)
+    }
+
+    /// Prints the jump target as an absolute offset in the chunk.
+    pub fn jump(
+        name: &str,
+        sign: i64,
+        chunk: &Chunk,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let jump = ((chunk.code[offset + 1] as u16) << 8) | chunk.code[offset + 2] as u16;
+        let target = offset as i64 + 3 + sign * jump as i64;
+        writeln!(out, "{:<16} {:4} -> {}", name, offset, target)?;
+        Ok(offset + 3)
+    }
+
+    /// A constant index of `width` bytes, big-endian, starting at `offset`.
+    fn index(chunk: &Chunk, offset: usize, width: usize) -> usize {
+        chunk.code[offset..offset + width]
+            .iter()
+            .fold(0, |index, &byte| (index << 8) | byte as usize)
+    }
+
+    pub fn invoke(
+        name: &str,
+        width: usize,
+        chunk: &Chunk,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let constant = index(chunk, offset + 1, width);
+        let arg_count = chunk.code[offset + 1 + width];
+        writeln!(
+            out,
+            "{:<16} ({} args) {:4} '{}'",
+            name, arg_count, constant, chunk.constants[constant]
+        )?;
+        Ok(offset + 2 + width)
+    }
+
+    pub fn closure(
+        name: &str,
+        width: usize,
+        chunk: &Chunk,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let constant = index(chunk, offset + 1, width);
+        let mut offset = offset + 1 + width;
+        let function = chunk.constants[constant];
+        writeln!(out, "{:<16} {:4} {}", name, constant, function)?;
+
+        for _ in 0..function.as_obj().as_function().upvalue_count {
+            let is_local = chunk.code[offset];
+            let index = chunk.code[offset + 1];
+            writeln!(
+                out,
+                "{:04}    |                     {} {}",
+                offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            )?;
+            offset += 2;
+        }
+
+        Ok(offset)
+    }
+
+    pub fn constant(
+        name: &str,
+        chunk: &Chunk,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         let constant = chunk.code[offset + 1];
-        print!("{:<16} {:4} '", name, constant);
-        print!("{:?}", chunk.constants[constant as usize]);
-        println!("'");
-        offset + 2
+        writeln!(
+            out,
+            "{:<16} {:4} '{}'",
+            name, constant, chunk.constants[constant as usize]
+        )?;
+        Ok(offset + 2)
+    }
+
+    pub fn constant_long(
+        name: &str,
+        chunk: &Chunk,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let constant = ((chunk.code[offset + 1] as usize) << 16)
+            | ((chunk.code[offset + 2] as usize) << 8)
+            | chunk.code[offset + 3] as usize;
+        writeln!(
+            out,
+            "{:<16} {:4} '{}'",
+            name, constant, chunk.constants[constant]
+        )?;
+        Ok(offset + 4)
     }
 }
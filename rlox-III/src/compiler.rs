@@ -1,13 +1,17 @@
 use crate::chunk::Chunk;
+use crate::memory::{GcRoots, Heap};
+use crate::object::*;
+use crate::optimizer;
 use crate::scanner::*;
+use crate::table::Table;
 use crate::value::Value;
-use crate::vm::*;
 use crate::OpCode;
 
 use crate::scanner::TokenType::*;
 
 use std::cell::Cell;
 use std::cell::RefCell;
+use std::fmt;
 use std::mem;
 
 #[allow(non_camel_case_types)]
@@ -45,7 +49,7 @@ impl Precedence {
     }
 }
 
-type ParseFn = fn(&mut Parser) -> ();
+type ParseFn = fn(&mut Parser, bool);
 
 pub struct ParseRule {
     pub idx: TokenType,
@@ -92,66 +96,282 @@ macro_rules! rule {
 const RULES: [ParseRule; 41] = [
     rule!(UNINIT, None, None, NONE), // UNINIT,
     // follow the text
-    rule!(LEFT_PAREN, grouping, None, NONE),
+    rule!(LEFT_PAREN, grouping, call, CALL),
     rule!(RIGHT_PAREN, None, None, NONE),
     rule!(LEFT_BRACE, None, None, NONE),
     rule!(RIGHT_BRACE, None, None, NONE),
     rule!(COMMA, None, None, NONE),
-    rule!(DOT, None, None, NONE),
+    rule!(DOT, None, dot, CALL),
     rule!(MINUS, unary, binary, TERM),
     rule!(PLUS, None, binary, TERM),
     rule!(SEMICOLON, None, None, NONE),
     rule!(SLASH, None, binary, FACTOR),
     rule!(STAR, None, binary, FACTOR),
-    rule!(BANG, None, None, NONE),
-    rule!(BANG_EQUAL, None, None, NONE),
+    rule!(BANG, unary, None, NONE),
+    rule!(BANG_EQUAL, None, binary, EQUALITY),
     rule!(EQUAL, None, None, NONE),
-    rule!(EQUAL_EQUAL, None, None, NONE),
-    rule!(GREATER, None, None, NONE),
-    rule!(GREATER_EQUAL, None, None, NONE),
-    rule!(LESS, None, None, NONE),
-    rule!(LESS_EQUAL, None, None, NONE),
-    rule!(IDENTIFIER, None, None, NONE),
-    rule!(STRING, None, None, NONE),
+    rule!(EQUAL_EQUAL, None, binary, EQUALITY),
+    rule!(GREATER, None, binary, COMPARISON),
+    rule!(GREATER_EQUAL, None, binary, COMPARISON),
+    rule!(LESS, None, binary, COMPARISON),
+    rule!(LESS_EQUAL, None, binary, COMPARISON),
+    rule!(IDENTIFIER, variable, None, NONE),
+    rule!(STRING, string, None, NONE),
     rule!(NUMBER, number, None, NONE),
-    rule!(AND, None, None, NONE),
+    rule!(AND, None, and_, AND),
     rule!(CLASS, None, None, NONE),
     rule!(ELSE, None, None, NONE),
-    rule!(FALSE, None, None, NONE),
+    rule!(FALSE, literal, None, NONE),
     rule!(FOR, None, None, NONE),
     rule!(FUN, None, None, NONE),
     rule!(IF, None, None, NONE),
-    rule!(NIL, None, None, NONE),
-    rule!(OR, None, None, NONE),
+    rule!(NIL, literal, None, NONE),
+    rule!(OR, None, or_, OR),
     rule!(PRINT, None, None, NONE),
     rule!(RETURN, None, None, NONE),
-    rule!(SUPER, None, None, NONE),
-    rule!(THIS, None, None, NONE),
-    rule!(TRUE, None, None, NONE),
+    rule!(SUPER, super_, None, NONE),
+    rule!(THIS, this_, None, NONE),
+    rule!(TRUE, literal, None, NONE),
     rule!(VAR, None, None, NONE),
     rule!(WHILE, None, None, NONE),
     rule!(ERROR, None, None, NONE),
     rule!(EOF, None, None, NONE),
 ];
 
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
+
+#[derive(Debug)]
+pub struct Local {
+    pub name: String,
+    /// -1 marks a declared but not yet initialized variable.
+    pub depth: i32,
+    pub is_captured: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upvalue {
+    pub index: u8,
+    /// true when capturing a local of the enclosing function,
+    /// false when capturing one of its upvalues.
+    pub is_local: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// One compiler per function being compiled; `enclosing` links to the outer one.
+#[derive(Debug)]
+pub struct Compiler {
+    pub enclosing: Option<Box<Compiler>>,
+    pub function: ObjFunction,
+    pub function_type: FunctionType,
+
+    pub locals: Vec<Local>,
+    pub upvalues: Vec<Upvalue>,
+    pub scope_depth: i32,
+    /// Identifier constants already in this function's chunk, by name.
+    pub identifiers: Table,
+    /// Where the last two instructions that can start a superinstruction
+    /// begin, newest last. See [Parser::last_fusable].
+    pub fusable: [Option<usize>; 2],
+    /// The furthest offset a forward jump lands on. Nothing may be fused
+    /// across it. Loops jump back to the start of a statement, which is
+    /// never inside a fused sequence.
+    pub jump_target: usize,
+}
+
+impl Compiler {
+    pub fn new(function_type: FunctionType, name: Option<ObjRef>) -> Self {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+        // Slot zero is claimed by the function being called,
+        // or by the receiver in methods.
+        let slot_zero = match function_type {
+            FunctionType::Function | FunctionType::Script => "",
+            FunctionType::Initializer | FunctionType::Method => "this",
+        };
+        locals.push(Local {
+            name: slot_zero.to_string(),
+            depth: 0,
+            is_captured: false,
+        });
+        let mut function = ObjFunction::new(name);
+        if !slot_zero.is_empty() {
+            function.locals.push(LocalName {
+                name: slot_zero.to_string(),
+                slot: 0,
+                start: 0,
+                end: usize::MAX,
+            });
+        }
+
+        Self {
+            enclosing: None,
+            function,
+            function_type,
+            locals,
+            upvalues: Vec::with_capacity(UINT8_COUNT),
+            scope_depth: 0,
+            identifiers: Table::new(),
+            fusable: [None, None],
+            jump_target: 0,
+        }
+    }
+
+    /// Ends the code range of the open [LocalName] for `slot` at `end`.
+    fn end_local_name(&mut self, slot: usize, end: usize) {
+        let open = self
+            .function
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot && local.end == usize::MAX);
+        if let Some(local) = open {
+            local.end = end;
+        }
+    }
+
+    /// Returns the slot of `name` and whether it is still uninitialized.
+    fn resolve_local(&self, name: &str) -> Option<(u8, bool)> {
+        self.locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(i, local)| (i as u8, local.depth == -1))
+    }
+
+    fn resolve_upvalue(&mut self, name: &str, errors: &mut Vec<&'static str>) -> Option<u8> {
+        let enclosing = self.enclosing.as_mut()?;
+
+        if let Some((local, uninitialized)) = enclosing.resolve_local(name) {
+            if uninitialized {
+                errors.push("Can't read local variable in its own initializer.");
+            }
+            enclosing.locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(local, true, errors));
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name, errors) {
+            return Some(self.add_upvalue(upvalue, false, errors));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool, errors: &mut Vec<&'static str>) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        if let Some(i) = self.upvalues.iter().position(|u| *u == upvalue) {
+            return i as u8;
+        }
+
+        if self.upvalues.len() == UINT8_COUNT {
+            errors.push("Too many closure variables in function.");
+            return 0;
+        }
+
+        self.upvalues.push(upvalue);
+        self.function.upvalue_count = self.upvalues.len();
+        (self.upvalues.len() - 1) as u8
+    }
+}
+
+/// The VM's roots plus every function still being compiled.
+struct CompilerRoots<'a> {
+    vm: &'a dyn GcRoots,
+    compiler: &'a Compiler,
+}
+
+impl GcRoots for CompilerRoots<'_> {
+    fn mark_roots(&self, heap: &mut Heap) {
+        self.vm.mark_roots(heap);
+
+        let mut compiler = Some(self.compiler);
+        while let Some(current) = compiler {
+            if let Some(name) = current.function.name {
+                heap.mark_object(name);
+            }
+            for constant in current.function.chunk.constants.iter() {
+                heap.mark_value(*constant);
+            }
+            compiler = current.enclosing.as_deref();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClassCompiler {
+    pub has_superclass: bool,
+}
+
 pub struct Parser<'src, 'c> {
     pub scanner: RefCell<Scanner<'src>>,
-    pub chunk: &'c mut Chunk,
+    pub heap: &'c mut Heap,
+    pub roots: &'c dyn GcRoots,
+    pub compiler: Box<Compiler>,
+    /// Innermost class being compiled is at the end.
+    pub class_compilers: Vec<ClassCompiler>,
     pub current: Token,
     pub previous: Token,
-    pub had_error: Cell<bool>,
+    /// Everything reported so far; the compile failed if this isn't empty.
+    pub errors: RefCell<Vec<CompileError>>,
     pub panic_mode: Cell<bool>,
+    pub options: CompileOptions,
+}
+
+/// An error in the source, found by [compile].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: i64,
+    pub column: i64,
+    /// What the error points at, like ` at 'x'` or ` at end`. Empty when
+    /// the scanner rejected the text.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] Error{}: {}",
+            self.line, self.column, self.location, self.message
+        )
+    }
+}
+
+/// Choices that change the code [compile] emits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompileOptions {
+    /// Run each finished chunk through the [optimizer].
+    pub optimize: bool,
+    /// Print the value of an expression left without a semicolon at the end
+    /// of the script, rather than reporting the missing `;`. For the REPL.
+    pub print_trailing_expression: bool,
 }
 
 impl<'src, 'c> Parser<'src, 'c> {
-    pub fn new(scanner: RefCell<Scanner<'src>>, chunk: &'c mut Chunk) -> Self {
+    pub fn new(
+        scanner: RefCell<Scanner<'src>>,
+        heap: &'c mut Heap,
+        roots: &'c dyn GcRoots,
+    ) -> Self {
         Self {
             scanner,
-            chunk,
+            heap,
+            roots,
+            compiler: Box::new(Compiler::new(FunctionType::Script, None)),
+            class_compilers: Vec::new(),
             current: Token::null(),
             previous: Token::null(),
-            had_error: Cell::new(false),
+            errors: RefCell::new(Vec::new()),
             panic_mode: Cell::new(false),
+            options: CompileOptions::default(),
         }
     }
 
@@ -178,32 +398,637 @@ impl<'src, 'c> Parser<'src, 'c> {
         self.error_at_current(message);
     }
 
-    pub fn end_compiler(&mut self) {
+    fn check(&self, tt: TokenType) -> bool {
+        self.current.token_type == tt
+    }
+
+    fn match_token(&mut self, tt: TokenType) -> bool {
+        if !self.check(tt) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        let roots = CompilerRoots {
+            vm: self.roots,
+            compiler: &self.compiler,
+        };
+        self.heap.alloc(kind, &roots)
+    }
+
+    fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let roots = CompilerRoots {
+            vm: self.roots,
+            compiler: &self.compiler,
+        };
+        self.heap.copy_string(chars, &roots)
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
+    fn push_compiler(&mut self, function_type: FunctionType) {
+        let name = self.previous.getstring();
+        let name = self.copy_string(&name);
+        let compiler = Box::new(Compiler::new(function_type, Some(name)));
+        let enclosing = mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(enclosing);
+    }
+
+    /// Finishes the current function and returns it with its captured upvalues,
+    /// restoring the enclosing compiler.
+    pub fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+        let end = self.current_chunk().code.len();
+        for local in self.compiler.function.locals.iter_mut() {
+            local.end = local.end.min(end);
+        }
+
+        if self.options.optimize && !self.had_error() {
+            optimizer::optimize(&mut self.compiler.function);
+        }
+
         #[cfg(feature = "print_code")]
         {
-            if self.had_error.get() {
-                self.chunk.disassemble("code");
+            if !self.had_error() {
+                let name = match self.compiler.function.name {
+                    Some(name) => name.to_string(),
+                    None => "<script>".to_string(),
+                };
+                print!("{}", self.current_chunk().disassemble(&name));
             }
         }
 
-        self.emit_return();
+        match self.compiler.enclosing.take() {
+            Some(enclosing) => {
+                let compiler = mem::replace(&mut self.compiler, enclosing);
+                (compiler.function, compiler.upvalues)
+            }
+            None => (
+                mem::replace(&mut self.compiler.function, ObjFunction::new(None)),
+                Vec::new(),
+            ),
+        }
     }
 
     pub fn expression(&mut self) {
         self.parse_precedence(Precedence::ASSIGNMENT);
     }
 
+    pub fn declaration(&mut self) {
+        if self.match_token(CLASS) {
+            self.class_declaration();
+        } else if self.match_token(FUN) {
+            self.fun_declaration();
+        } else if self.match_token(VAR) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode.get() {
+            self.synchronize();
+        }
+    }
+
+    pub fn statement(&mut self) {
+        if self.match_token(PRINT) {
+            self.print_statement();
+        } else if self.match_token(FOR) {
+            self.for_statement();
+        } else if self.match_token(IF) {
+            self.if_statement();
+        } else if self.match_token(RETURN) {
+            self.return_statement();
+        } else if self.match_token(WHILE) {
+            self.while_statement();
+        } else if self.match_token(LEFT_BRACE) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(RIGHT_BRACE) && !self.check(EOF) {
+            self.declaration();
+        }
+
+        self.consume(RIGHT_BRACE, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        let end = self.current_chunk().code.len();
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth <= self.compiler.scope_depth {
+                break;
+            }
+            let is_captured = local.is_captured;
+            let slot = self.compiler.locals.len() - 1;
+            self.compiler.end_local_name(slot, end);
+            if is_captured {
+                self.emit_byte(OpCode::OP_CLOSE_UPVALUE as u8);
+            } else {
+                self.emit_byte(OpCode::OP_POP as u8);
+            }
+            self.compiler.locals.pop();
+        }
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        self.push_compiler(function_type);
+        self.begin_scope();
+
+        self.consume(LEFT_PAREN, "Expect '(' after function name.");
+        if !self.check(RIGHT_PAREN) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(RIGHT_PAREN, "Expect ')' after parameters.");
+        self.consume(LEFT_BRACE, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.alloc(ObjKind::Function(function));
+        let constant = self.make_constant(&Value::obj(function));
+        self.emit_constant_op(OpCode::OP_CLOSURE, OpCode::OP_CLOSURE_LONG, constant);
+
+        for upvalue in upvalues.iter() {
+            self.emit_byte(if upvalue.is_local { 1 } else { 0 });
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(IDENTIFIER, "Expect method name.");
+        let name = self.previous.getstring();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_constant_op(OpCode::OP_METHOD, OpCode::OP_METHOD_LONG, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(IDENTIFIER, "Expect class name.");
+        let class_name = self.previous.getstring();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_constant_op(OpCode::OP_CLASS, OpCode::OP_CLASS_LONG, name_constant);
+        self.define_variable(name_constant);
+
+        self.class_compilers.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(LESS) {
+            self.consume(IDENTIFIER, "Expect superclass name.");
+            parsefun::variable(self, false);
+
+            if class_name == self.previous.strref() {
+                self.error("A class can't inherit from itself.");
+            }
+
+            self.begin_scope();
+            self.add_local("super".to_string());
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte(OpCode::OP_INHERIT as u8);
+            self.class_compilers.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(&class_name, false);
+        self.consume(LEFT_BRACE, "Expect '{' before class body.");
+        while !self.check(RIGHT_BRACE) && !self.check(EOF) {
+            self.method();
+        }
+        self.consume(RIGHT_BRACE, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        if self.class_compilers.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(EQUAL) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::OP_NIL as u8);
+        }
+        self.consume(SEMICOLON, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(SEMICOLON, "Expect ';' after value.");
+        self.emit_byte(OpCode::OP_PRINT as u8);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(LEFT_PAREN, "Expect '(' after 'for'.");
+        if self.match_token(SEMICOLON) {
+            // No initializer.
+        } else if self.match_token(VAR) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(SEMICOLON) {
+            self.expression();
+            self.consume(SEMICOLON, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8));
+            self.emit_byte(OpCode::OP_POP as u8);
+        }
+
+        if !self.match_token(RIGHT_PAREN) {
+            let body_jump = self.emit_jump(OpCode::OP_JUMP as u8);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::OP_POP as u8);
+            self.consume(RIGHT_PAREN, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OP_POP as u8);
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(LEFT_PAREN, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(RIGHT_PAREN, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::OP_JUMP as u8);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        if self.match_token(ELSE) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(SEMICOLON) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                // Like rlox-II, `return nil;` is accepted and still returns the instance.
+                if self.match_token(NIL) && self.match_token(SEMICOLON) {
+                    self.emit_return();
+                    return;
+                }
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(SEMICOLON, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OP_RETURN as u8);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(LEFT_PAREN, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(RIGHT_PAREN, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE as u8);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        if self.options.print_trailing_expression
+            && self.compiler.enclosing.is_none()
+            && self.check(EOF)
+        {
+            self.emit_byte(OpCode::OP_PRINT as u8);
+            return;
+        }
+        self.consume(SEMICOLON, "Expect ';' after expression.");
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode.set(false);
+
+        while self.current.token_type != EOF {
+            if self.previous.token_type == SEMICOLON {
+                return;
+            }
+            match self.current.token_type {
+                CLASS | FUN | VAR | FOR | IF | WHILE | PRINT | RETURN => {
+                    return;
+                }
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(IDENTIFIER, message);
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.previous.getstring();
+        self.identifier_constant(&name)
+    }
+
+    fn declare_variable(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous.getstring();
+        let mut duplicated = false;
+        for local in self.compiler.locals.iter().rev() {
+            if local.depth != -1 && local.depth < self.compiler.scope_depth {
+                break;
+            }
+            if local.name == name {
+                duplicated = true;
+                break;
+            }
+        }
+        if duplicated {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.compiler.locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
+    }
+
+    fn resolve_local(&mut self, name: &str) -> Option<u8> {
+        let (slot, uninitialized) = self.compiler.resolve_local(name)?;
+        if uninitialized {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot)
+    }
+
+    fn resolve_upvalue(&mut self, name: &str) -> Option<u8> {
+        let mut errors = Vec::new();
+        let upvalue = self.compiler.resolve_upvalue(name, &mut errors);
+        for message in errors {
+            self.error(message);
+        }
+        upvalue
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+        let depth = self.compiler.scope_depth;
+        let start = self.current_chunk().code.len();
+        let slot = self.compiler.locals.len() - 1;
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = depth;
+            let name = LocalName {
+                name: local.name.clone(),
+                slot,
+                start,
+                end: usize::MAX,
+            };
+            self.compiler.function.locals.push(name);
+        }
+    }
+
+    /// Interned names let every use of an identifier share one constant slot.
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let string = self.copy_string(name);
+        if let Some(index) = self.compiler.identifiers.get(string) {
+            return index.as_number() as usize;
+        }
+
+        let index = self.make_constant(&Value::obj(string));
+        self.compiler
+            .identifiers
+            .set(string, Value::number(index as f64));
+        index
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_constant_op(
+            OpCode::OP_DEFINE_GLOBAL,
+            OpCode::OP_DEFINE_GLOBAL_LONG,
+            global,
+        );
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(name) {
+            (OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL, slot)
+        } else if let Some(upvalue) = self.resolve_upvalue(name) {
+            (OpCode::OP_GET_UPVALUE, OpCode::OP_SET_UPVALUE, upvalue)
+        } else {
+            let arg = self.identifier_constant(name);
+            if can_assign && self.match_token(EQUAL) {
+                self.expression();
+                self.emit_constant_op(OpCode::OP_SET_GLOBAL, OpCode::OP_SET_GLOBAL_LONG, arg);
+            } else {
+                self.emit_constant_op(OpCode::OP_GET_GLOBAL, OpCode::OP_GET_GLOBAL_LONG, arg);
+            }
+            return;
+        };
+
+        if can_assign && self.match_token(EQUAL) {
+            self.expression();
+            if matches!(set_op, OpCode::OP_SET_LOCAL) && self.fuse_increment(arg) {
+                return;
+            }
+            self.emit_bytes((set_op as u8, arg));
+        } else if matches!(get_op, OpCode::OP_GET_LOCAL) {
+            self.emit_get_local(arg);
+        } else {
+            self.emit_bytes((get_op as u8, arg));
+        }
+    }
+
+    fn emit_get_local(&mut self, slot: u8) {
+        self.mark_fusable();
+        match slot {
+            0 => self.emit_byte(OpCode::OP_GET_LOCAL_0 as u8),
+            1 => self.emit_byte(OpCode::OP_GET_LOCAL_1 as u8),
+            2 => self.emit_byte(OpCode::OP_GET_LOCAL_2 as u8),
+            3 => self.emit_byte(OpCode::OP_GET_LOCAL_3 as u8),
+            _ => self.emit_bytes((OpCode::OP_GET_LOCAL as u8, slot)),
+        }
+    }
+
+    /// Notes that the next instruction emitted may start a superinstruction.
+    fn mark_fusable(&mut self) {
+        let offset = self.current_chunk().code.len();
+        let fusable = &mut self.compiler.fusable;
+        fusable[0] = fusable[1];
+        fusable[1] = Some(offset);
+    }
+
+    /// The offset and opcode of the last instruction emitted, if it was
+    /// marked fusable and no jump lands after its start.
+    fn last_fusable(&mut self) -> Option<(usize, OpCode)> {
+        let end = self.current_chunk().code.len();
+        self.fusable_ending_at(self.compiler.fusable[1]?, end)
+    }
+
+    /// Like [Parser::last_fusable], for the marked instruction before it.
+    fn previous_fusable(&mut self) -> Option<(usize, OpCode)> {
+        let (last, _) = self.last_fusable()?;
+        self.fusable_ending_at(self.compiler.fusable[0]?, last)
+    }
+
+    fn fusable_ending_at(&mut self, offset: usize, end: usize) -> Option<(usize, OpCode)> {
+        if self.compiler.jump_target > offset {
+            return None;
+        }
+        let opcode = OpCode::from(self.current_chunk().code[offset]);
+        let length = match opcode {
+            OpCode::OP_GET_LOCAL_0
+            | OpCode::OP_GET_LOCAL_1
+            | OpCode::OP_GET_LOCAL_2
+            | OpCode::OP_GET_LOCAL_3
+            | OpCode::OP_LESS => 1,
+            OpCode::OP_GET_LOCAL | OpCode::OP_CONSTANT | OpCode::OP_ADD_CONSTANT => 2,
+            _ => return None,
+        };
+        if offset + length == end {
+            Some((offset, opcode))
+        } else {
+            None
+        }
+    }
+
+    /// Replaces `local + 1` at the end of the code with OP_INCREMENT_LOCAL
+    /// when it's being assigned back to the same local.
+    fn fuse_increment(&mut self, slot: u8) -> bool {
+        let add = match self.last_fusable() {
+            Some((offset, OpCode::OP_ADD_CONSTANT)) => offset,
+            _ => return false,
+        };
+        let (get, local) = match self.previous_fusable() {
+            Some((offset, OpCode::OP_GET_LOCAL)) => (offset, self.current_chunk().code[offset + 1]),
+            Some((offset, OpCode::OP_GET_LOCAL_0)) => (offset, 0),
+            Some((offset, OpCode::OP_GET_LOCAL_1)) => (offset, 1),
+            Some((offset, OpCode::OP_GET_LOCAL_2)) => (offset, 2),
+            Some((offset, OpCode::OP_GET_LOCAL_3)) => (offset, 3),
+            _ => return false,
+        };
+        if local != slot {
+            return false;
+        }
+        let constant = self.current_chunk().code[add + 1] as usize;
+        if self.current_chunk().constants[constant] != Value::number(1.0) {
+            return false;
+        }
+
+        self.current_chunk().truncate(get);
+        self.compiler.fusable = [None, None];
+        self.emit_bytes((OpCode::OP_INCREMENT_LOCAL as u8, slot));
+        true
+    }
+
     pub fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
         let rule = parsefun::get_rule(self.previous.token_type);
+        let can_assign = precedence as usize <= Precedence::ASSIGNMENT as usize;
         match rule.prefix {
             None => {
-                dbg!(self.previous.token_type);
-                dbg!(rule.idx);
                 self.error("Expect expression.");
                 return;
             }
-            Some(prefix_rule) => prefix_rule(self),
+            Some(prefix_rule) => prefix_rule(self, can_assign),
         }
 
         let precedence = precedence as usize;
@@ -215,32 +1040,125 @@ impl<'src, 'c> Parser<'src, 'c> {
                     self.error("Expect valid rule.");
                     return;
                 }
-                Some(infix_rule) => infix_rule(self),
+                Some(infix_rule) => infix_rule(self, can_assign),
             }
         }
+
+        if can_assign && self.match_token(EQUAL) {
+            self.error("Invalid assignment target.");
+        }
     }
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(&value);
-        self.emit_bytes((OpCode::OP_CONSTANT as u8, constant));
+        self.emit_constant_op(OpCode::OP_CONSTANT, OpCode::OP_CONSTANT_LONG, constant);
+    }
+
+    /// Emits `op` with a one-byte operand, or `long_op` with a 24-bit
+    /// big-endian one once the constant index no longer fits in a byte.
+    fn emit_constant_op(&mut self, op: OpCode, long_op: OpCode, index: usize) {
+        if index <= u8::MAX as usize {
+            if op as u8 == OpCode::OP_CONSTANT as u8 {
+                self.mark_fusable();
+            }
+            self.emit_bytes((op as u8, index as u8));
+        } else {
+            self.emit_byte(long_op as u8);
+            self.emit_byte((index >> 16) as u8);
+            self.emit_bytes(((index >> 8) as u8, index as u8));
+        }
     }
 
-    fn make_constant(&mut self, value: &Value) -> u8 {
-        let constant = self.chunk.add_constant(value.0.get());
-        if constant > (u8::MAX as usize) {
+    fn make_constant(&mut self, value: &Value) -> usize {
+        let constant = self.current_chunk().add_constant(*value);
+        if constant > MAX_LONG_CONSTANT {
             self.error("Too many constants in one chunk.");
             return 0;
         }
-        constant as u8
+        constant
+    }
+
+    fn emit_jump(&mut self, instruction: u8) -> usize {
+        match self.last_fusable() {
+            Some((offset, OpCode::OP_LESS)) if instruction == OpCode::OP_JUMP_IF_FALSE as u8 => {
+                self.current_chunk().code[offset] = OpCode::OP_LESS_JUMP_IF_FALSE as u8;
+            }
+            _ => self.emit_byte(instruction),
+        }
+        self.emit_bytes((0xff, 0xff));
+        self.current_chunk().code.len() - 2
+    }
+
+    /// Back-patches the operand of the jump at `offset` to land on the current end of code.
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
+        self.compiler.jump_target = self.current_chunk().code.len();
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::OP_LOOP as u8);
+
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit_byte(((offset >> 8) & 0xff) as u8);
+        self.emit_byte((offset & 0xff) as u8);
+    }
+
+    /// Emits OP_ADD, or fuses it into the number constant just pushed.
+    fn emit_add(&mut self) {
+        if let Some((offset, OpCode::OP_CONSTANT)) = self.last_fusable() {
+            let constant = self.current_chunk().code[offset + 1] as usize;
+            if self.current_chunk().constants[constant].is_number() {
+                self.current_chunk().code[offset] = OpCode::OP_ADD_CONSTANT as u8;
+                return;
+            }
+        }
+        self.emit_byte(OpCode::OP_ADD as u8);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(RIGHT_PAREN) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(RIGHT_PAREN, "Expect ')' after arguments.");
+        arg_count as u8
     }
 
     fn emit_return(&mut self) {
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_get_local(0);
+        } else {
+            self.emit_byte(OpCode::OP_NIL as u8);
+        }
         self.emit_byte(OpCode::OP_RETURN as u8);
     }
 
     fn emit_byte(&mut self, byte: u8) {
         let line = self.previous.line as i32;
-        self.chunk.write(byte, line);
+        let column = self.previous.column as i32;
+        self.current_chunk().write(byte, line, column);
     }
 
     fn emit_bytes(&mut self, byte: (u8, u8)) {
@@ -262,19 +1180,21 @@ impl<'src, 'c> Parser<'src, 'c> {
         }
         self.panic_mode.set(true);
 
-        eprint!("[line {}] Error", token.line);
-        match token.token_type {
-            EOF => {
-                eprint!(" at end");
-            }
-            ERROR => { //skip
-            }
-            _ => {
-                eprint!(" at '{}'", token.strref());
-            }
-        }
-        eprintln!(": {}", message);
-        self.had_error.set(true);
+        let location = match token.token_type {
+            EOF => " at end".to_string(),
+            ERROR => String::new(),
+            _ => format!(" at '{}'", token.strref()),
+        };
+        self.errors.borrow_mut().push(CompileError {
+            line: token.line,
+            column: token.column,
+            location,
+            message: message.to_string(),
+        });
+    }
+
+    fn had_error(&self) -> bool {
+        !self.errors.borrow().is_empty()
     }
 }
 
@@ -285,37 +1205,142 @@ mod parsefun {
         RULES.get(operator_type as usize).unwrap()
     }
 
-    pub fn number(scanneer: &mut Parser) {
-        let value: f64 = scanneer.previous.strref().parse().unwrap_or_else(|_| 0.0);
-        scanneer.emit_constant(Value::new(value));
+    pub fn number(scanneer: &mut Parser, _can_assign: bool) {
+        let value: f64 = scanneer.previous.strref().parse().unwrap_or(0.0);
+        scanneer.emit_constant(Value::number(value));
     }
 
-    pub fn grouping(scanneer: &mut Parser) {
+    pub fn string(scanneer: &mut Parser, _can_assign: bool) {
+        let lexeme = scanneer.previous.getstring();
+        // Trim the leading and trailing quotation marks.
+        let chars = &lexeme[1..lexeme.len() - 1];
+        let string = scanneer.copy_string(chars);
+        scanneer.emit_constant(Value::obj(string));
+    }
+
+    pub fn variable(scanneer: &mut Parser, can_assign: bool) {
+        let name = scanneer.previous.getstring();
+        scanneer.named_variable(&name, can_assign);
+    }
+
+    pub fn call(scanneer: &mut Parser, _can_assign: bool) {
+        let arg_count = scanneer.argument_list();
+        scanneer.emit_bytes((OP_CALL as u8, arg_count));
+    }
+
+    pub fn dot(scanneer: &mut Parser, can_assign: bool) {
+        scanneer.consume(IDENTIFIER, "Expect property name after '.'.");
+        let name = scanneer.previous.getstring();
+        let name = scanneer.identifier_constant(&name);
+
+        if can_assign && scanneer.match_token(EQUAL) {
+            scanneer.expression();
+            scanneer.emit_constant_op(OP_SET_PROPERTY, OP_SET_PROPERTY_LONG, name);
+        } else if scanneer.match_token(LEFT_PAREN) {
+            let arg_count = scanneer.argument_list();
+            scanneer.emit_constant_op(OP_INVOKE, OP_INVOKE_LONG, name);
+            scanneer.emit_byte(arg_count);
+        } else {
+            scanneer.emit_constant_op(OP_GET_PROPERTY, OP_GET_PROPERTY_LONG, name);
+        }
+    }
+
+    pub fn this_(scanneer: &mut Parser, _can_assign: bool) {
+        if scanneer.class_compilers.is_empty() {
+            scanneer.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        variable(scanneer, false);
+    }
+
+    pub fn super_(scanneer: &mut Parser, _can_assign: bool) {
+        match scanneer.class_compilers.last() {
+            None => scanneer.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                scanneer.error("Can't use 'super' in a class with no superclass.");
+            }
+            _ => {}
+        }
+
+        scanneer.consume(DOT, "Expect '.' after 'super'.");
+        scanneer.consume(IDENTIFIER, "Expect superclass method name.");
+        let name = scanneer.previous.getstring();
+        let name = scanneer.identifier_constant(&name);
+
+        scanneer.named_variable("this", false);
+        if scanneer.match_token(LEFT_PAREN) {
+            let arg_count = scanneer.argument_list();
+            scanneer.named_variable("super", false);
+            scanneer.emit_constant_op(OP_SUPER_INVOKE, OP_SUPER_INVOKE_LONG, name);
+            scanneer.emit_byte(arg_count);
+        } else {
+            scanneer.named_variable("super", false);
+            scanneer.emit_constant_op(OP_GET_SUPER, OP_GET_SUPER_LONG, name);
+        }
+    }
+
+    pub fn and_(scanneer: &mut Parser, _can_assign: bool) {
+        let end_jump = scanneer.emit_jump(OP_JUMP_IF_FALSE as u8);
+
+        scanneer.emit_byte(OP_POP as u8);
+        scanneer.parse_precedence(Precedence::AND);
+
+        scanneer.patch_jump(end_jump);
+    }
+
+    pub fn or_(scanneer: &mut Parser, _can_assign: bool) {
+        let else_jump = scanneer.emit_jump(OP_JUMP_IF_FALSE as u8);
+        let end_jump = scanneer.emit_jump(OP_JUMP as u8);
+
+        scanneer.patch_jump(else_jump);
+        scanneer.emit_byte(OP_POP as u8);
+
+        scanneer.parse_precedence(Precedence::OR);
+        scanneer.patch_jump(end_jump);
+    }
+
+    pub fn literal(scanneer: &mut Parser, _can_assign: bool) {
+        match scanneer.previous.token_type {
+            FALSE => scanneer.emit_byte(OP_FALSE as u8),
+            NIL => scanneer.emit_byte(OP_NIL as u8),
+            TRUE => scanneer.emit_byte(OP_TRUE as u8),
+            _ => unreachable!("Maybe a bug"),
+        }
+    }
+
+    pub fn grouping(scanneer: &mut Parser, _can_assign: bool) {
         scanneer.expression();
         scanneer.consume(RIGHT_PAREN, "Expect ')' after expression.");
     }
 
-    pub fn unary(scanneer: &mut Parser) {
+    pub fn unary(scanneer: &mut Parser, _can_assign: bool) {
         let operator_type = scanneer.previous.token_type;
         scanneer.parse_precedence(Precedence::UNARY);
 
         match operator_type {
-            MINUS => {
-                scanneer.emit_byte(OP_NEGATE as u8);
-            }
-            _ => {
-                return;
-            }
+            BANG => scanneer.emit_byte(OP_NOT as u8),
+            MINUS => scanneer.emit_byte(OP_NEGATE as u8),
+            _ => {}
         }
     }
 
-    pub fn binary(scanneer: &mut Parser) {
+    pub fn binary(scanneer: &mut Parser, _can_assign: bool) {
         let operator_type = scanneer.previous.token_type;
         let rule: &ParseRule = get_rule(operator_type);
         scanneer.parse_precedence(rule.precedence.succ());
 
         match operator_type {
-            PLUS => scanneer.emit_byte(OP_ADD as u8),
+            BANG_EQUAL => scanneer.emit_bytes((OP_EQUAL as u8, OP_NOT as u8)),
+            EQUAL_EQUAL => scanneer.emit_byte(OP_EQUAL as u8),
+            GREATER => scanneer.emit_byte(OP_GREATER as u8),
+            GREATER_EQUAL => scanneer.emit_bytes((OP_LESS as u8, OP_NOT as u8)),
+            LESS => {
+                scanneer.mark_fusable();
+                scanneer.emit_byte(OP_LESS as u8);
+            }
+            LESS_EQUAL => scanneer.emit_bytes((OP_GREATER as u8, OP_NOT as u8)),
+            PLUS => scanneer.emit_add(),
             MINUS => scanneer.emit_byte(OP_SUBTRACT as u8),
             STAR => scanneer.emit_byte(OP_MULTIPLY as u8),
             SLASH => scanneer.emit_byte(OP_DIVIDE as u8),
@@ -326,17 +1351,66 @@ mod parsefun {
     }
 }
 
-pub fn compile(source: String, chunk: &mut Chunk) -> InterpretResult {
+/// Compiles the whole source into the top-level script function.
+pub fn compile(
+    source: String,
+    heap: &mut Heap,
+    roots: &dyn GcRoots,
+    options: CompileOptions,
+) -> Result<ObjRef, Vec<CompileError>> {
     let scanner = Scanner::new(&source);
-    let mut parser = Parser::new(RefCell::new(scanner), chunk);
+    let mut parser = Parser::new(RefCell::new(scanner), heap, roots);
+    parser.options = options;
+
+    parser.advance();
+    while !parser.match_token(EOF) {
+        parser.declaration();
+    }
+    let (function, _) = parser.end_compiler();
+    if !parser.had_error() {
+        Ok(parser.alloc(ObjKind::Function(function)))
+    } else {
+        Err(parser.errors.into_inner())
+    }
+}
+
+/// Compiles `source`, a single expression, into a function returning its
+/// value. The function takes one parameter per name in `params`, and with
+/// `has_this` it is compiled as a method so `this` reads slot zero. The
+/// debugger evaluates expressions in a frame this way, passing its locals.
+pub fn compile_expression(
+    source: &str,
+    params: &[String],
+    has_this: bool,
+    heap: &mut Heap,
+    roots: &dyn GcRoots,
+) -> Result<ObjRef, Vec<CompileError>> {
+    let scanner = Scanner::new(source);
+    let mut parser = Parser::new(RefCell::new(scanner), heap, roots);
+    let function_type = if has_this {
+        parser.class_compilers.push(ClassCompiler {
+            has_superclass: false,
+        });
+        FunctionType::Method
+    } else {
+        FunctionType::Function
+    };
+    parser.compiler = Box::new(Compiler::new(function_type, None));
+    parser.begin_scope();
+    for name in params {
+        parser.add_local(name.clone());
+        parser.mark_initialized();
+    }
+    parser.compiler.function.arity = params.len();
 
     parser.advance();
     parser.expression();
     parser.consume(EOF, "Expect end of expression.");
-    parser.end_compiler();
-    if !parser.had_error.get() {
-        Ok(())
+    parser.emit_byte(OpCode::OP_RETURN as u8);
+    let (function, _) = parser.end_compiler();
+    if !parser.had_error() {
+        Ok(parser.alloc(ObjKind::Function(function)))
     } else {
-        Err(InterpretErrorCode::CompileError)
+        Err(parser.errors.into_inner())
     }
 }
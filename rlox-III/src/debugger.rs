@@ -0,0 +1,276 @@
+//! A line-oriented debugger for `--debug`. The VM hands it control before
+//! every instruction; it stops on breakpoints and steps, reads commands from
+//! its input and answers on its output.
+//!
+//! Lox scripts are a single file, so a breakpoint is a line of it. Breakpoints
+//! and steps trigger on the first instruction of a line, going by the line
+//! table of each chunk.
+
+use crate::host::TraceLine;
+use crate::object::{ObjFunction, ObjKind};
+use crate::vm::Vm;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+const HELP: &str = "\
+break [file:]line   b   stop when that line starts
+delete [file:]line  d   remove a breakpoint
+step                s   run to the next line, entering calls
+next                n   run to the next line, stepping over calls
+finish              f   run until the current function returns
+continue            c   run to the next breakpoint
+backtrace           bt  list the call frames, innermost first
+frame n                 select frame n for locals and print
+locals              l   show the selected frame's locals
+stack                   show every value on the VM stack
+print expr          p   evaluate expr in the selected frame
+quit                q   stop the program";
+
+/// When to stop next, besides breakpoints. Depths count frames on the call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Step,
+    Next(usize),
+    Finish(usize),
+    Continue,
+}
+
+pub struct Debugger {
+    path: String,
+    source: Vec<String>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    breakpoints: BTreeSet<i32>,
+    /// Lines with code in any function, found once the script is loaded.
+    code_lines: Option<BTreeSet<i32>>,
+    mode: Mode,
+    /// The line last run in each frame, outermost first, to spot new lines.
+    last_lines: Vec<i32>,
+    /// The frame `locals` and `print` look at, counted out from the innermost.
+    frame: usize,
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Debugger({})", self.path)
+    }
+}
+
+impl Debugger {
+    /// A debugger for the script at `path` with the text `source`. It stops
+    /// before the first line so breakpoints can be set.
+    pub fn new(path: &str, source: &str, input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Debugger {
+            path: path.to_string(),
+            source: source.lines().map(|line| line.to_string()).collect(),
+            input,
+            output,
+            breakpoints: BTreeSet::new(),
+            code_lines: None,
+            mode: Mode::Step,
+            last_lines: Vec::new(),
+            frame: 0,
+        }
+    }
+
+    /// Called by the VM before each instruction. Returns false when the
+    /// user quits, to stop the run.
+    pub(crate) fn before_instruction(&mut self, vm: &mut Vm) -> bool {
+        let (depth, line) = vm.position();
+        self.last_lines.resize(depth, -1);
+        let new_line = self.last_lines[depth - 1] != line;
+        self.last_lines[depth - 1] = line;
+
+        let stop = match self.mode {
+            Mode::Step => new_line,
+            Mode::Next(from) => new_line && depth <= from,
+            Mode::Finish(from) => depth < from,
+            Mode::Continue => false,
+        };
+        let breakpoint = new_line && self.breakpoints.contains(&line);
+        if stop || breakpoint {
+            if self.code_lines.is_none() {
+                self.code_lines = vm.script().map(code_lines);
+            }
+            match self.prompt(vm, breakpoint) {
+                Ok(go_on) => return go_on,
+                // Without a terminal to talk to, let the program finish.
+                Err(_) => self.detach(),
+            }
+        }
+        true
+    }
+
+    fn detach(&mut self) {
+        self.breakpoints.clear();
+        self.mode = Mode::Continue;
+    }
+
+    /// Reports where the run stopped and runs commands until one resumes it,
+    /// returning true, or quits, returning false.
+    fn prompt(&mut self, vm: &mut Vm, breakpoint: bool) -> io::Result<bool> {
+        self.frame = 0;
+        let what = if breakpoint { "Breakpoint" } else { "Stopped" };
+        let trace = vm.stack_trace();
+        writeln!(self.output, "{} at {}", what, self.location(&trace[0]))?;
+        self.show_line(trace[0].line)?;
+
+        loop {
+            write!(self.output, "(debug) ")?;
+            self.output.flush()?;
+            let mut command = String::new();
+            if self.input.read_line(&mut command)? == 0 {
+                writeln!(self.output)?;
+                self.detach();
+                return Ok(true);
+            }
+            let command = command.trim();
+            let (name, arg) = match command.find(' ') {
+                Some(space) => (&command[..space], command[space + 1..].trim()),
+                None => (command, ""),
+            };
+            let depth = trace.len();
+
+            match name {
+                "" => {}
+                "s" | "step" => {
+                    self.mode = Mode::Step;
+                    return Ok(true);
+                }
+                "n" | "next" => {
+                    self.mode = Mode::Next(depth);
+                    return Ok(true);
+                }
+                "f" | "finish" => {
+                    self.mode = Mode::Finish(depth);
+                    return Ok(true);
+                }
+                "c" | "continue" => {
+                    self.mode = Mode::Continue;
+                    return Ok(true);
+                }
+                "b" | "break" => self.set_breakpoint(arg)?,
+                "d" | "delete" => self.delete_breakpoint(arg)?,
+                "bt" | "backtrace" => {
+                    for (i, line) in trace.iter().enumerate() {
+                        let marker = if i == self.frame { '*' } else { ' ' };
+                        writeln!(self.output, "{}#{} {}", marker, i, self.location(line))?;
+                    }
+                }
+                "frame" => match arg.parse::<usize>() {
+                    Ok(frame) if frame < depth => {
+                        self.frame = frame;
+                        writeln!(self.output, "#{} {}", frame, self.location(&trace[frame]))?;
+                        self.show_line(trace[frame].line)?;
+                    }
+                    _ => writeln!(self.output, "No frame '{}'.", arg)?,
+                },
+                "l" | "locals" => {
+                    let locals = vm.locals(self.frame);
+                    if locals.is_empty() {
+                        writeln!(self.output, "No locals.")?;
+                    }
+                    for (name, value) in locals {
+                        writeln!(self.output, "{} = {}", name, value)?;
+                    }
+                }
+                "stack" => {
+                    for value in vm.stack_values() {
+                        write!(self.output, "[ {} ]", value)?;
+                    }
+                    writeln!(self.output)?;
+                }
+                "p" | "print" => match vm.evaluate(self.frame, arg) {
+                    Ok(value) => writeln!(self.output, "{}", value)?,
+                    Err(e) => writeln!(self.output, "{}", e)?,
+                },
+                "h" | "help" => writeln!(self.output, "{}", HELP)?,
+                "q" | "quit" => {
+                    self.output.flush()?;
+                    return Ok(false);
+                }
+                _ => writeln!(self.output, "Unknown command '{}'. Try 'help'.", name)?,
+            }
+        }
+    }
+
+    fn location(&self, line: &TraceLine) -> String {
+        match &line.function {
+            Some(name) => format!("{}:{} in {}()", self.path, line.line, name),
+            None => format!("{}:{} in script", self.path, line.line),
+        }
+    }
+
+    fn show_line(&mut self, line: i32) -> io::Result<()> {
+        let text = (line as usize)
+            .checked_sub(1)
+            .and_then(|index| self.source.get(index))
+            .map_or(String::new(), |text| text.clone());
+        writeln!(self.output, "{:>4}  {}", line, text)
+    }
+
+    /// Parses `[file:]line`. The file has to be the script, by path or name.
+    fn parse_line(&self, spec: &str) -> Result<i32, String> {
+        let (file, line) = match spec.rfind(':') {
+            Some(colon) => (Some(&spec[..colon]), &spec[colon + 1..]),
+            None => (None, spec),
+        };
+        if let Some(file) = file {
+            let script = Path::new(&self.path);
+            if Path::new(file) != script && script.file_name() != Some(file.as_ref()) {
+                return Err(format!("No source file named '{}'.", file));
+            }
+        }
+        match line.parse::<i32>() {
+            Ok(line) if line > 0 => Ok(line),
+            _ => Err(format!("Expect a line number, not '{}'.", line)),
+        }
+    }
+
+    /// Breaks on the first line at or after the one asked for that has code.
+    fn set_breakpoint(&mut self, spec: &str) -> io::Result<()> {
+        let line = match self.parse_line(spec) {
+            Ok(line) => line,
+            Err(message) => return writeln!(self.output, "{}", message),
+        };
+        let code_lines = self.code_lines.as_ref().expect("found at the first stop");
+        match code_lines.range(line..).next() {
+            Some(&line) => {
+                self.breakpoints.insert(line);
+                writeln!(self.output, "Breakpoint at {}:{}", self.path, line)
+            }
+            None => writeln!(self.output, "No code at or after line {}.", line),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, spec: &str) -> io::Result<()> {
+        match self.parse_line(spec) {
+            Ok(line) if self.breakpoints.remove(&line) => {
+                writeln!(self.output, "Deleted breakpoint at {}:{}", self.path, line)
+            }
+            Ok(line) => writeln!(self.output, "No breakpoint at line {}.", line),
+            Err(message) => writeln!(self.output, "{}", message),
+        }
+    }
+}
+
+/// Every line `function` or a function nested in it has code on.
+fn code_lines(function: &ObjFunction) -> BTreeSet<i32> {
+    let mut lines = BTreeSet::new();
+    add_code_lines(function, &mut lines);
+    lines
+}
+
+fn add_code_lines(function: &ObjFunction, lines: &mut BTreeSet<i32>) {
+    let chunk = &function.chunk;
+    lines.extend((0..chunk.code.len()).map(|offset| chunk.get_line(offset)));
+    for constant in chunk.constants.iter().filter(|constant| constant.is_obj()) {
+        let obj = constant.as_obj();
+        if let ObjKind::Function(nested) = &obj.kind {
+            add_code_lines(nested, lines);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Types for programs that embed the VM: values and errors they can hold on
+//! to without touching the garbage-collected heap.
+
+use crate::compiler::CompileError;
+use crate::value::Value;
+
+use std::error::Error;
+use std::fmt;
+use std::time::Instant;
+
+/// A Lox value copied out of the VM. Strings are owned, so they stay valid
+/// after the VM collects or is dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Any other object, by its printed form. It can't be passed back in.
+    Object(String),
+}
+
+impl From<Value> for LoxValue {
+    fn from(value: Value) -> Self {
+        if value.is_nil() {
+            LoxValue::Nil
+        } else if value.is_bool() {
+            LoxValue::Bool(value.as_bool())
+        } else if value.is_number() {
+            LoxValue::Number(value.as_number())
+        } else if value.is_string() {
+            LoxValue::String(value.as_obj().as_string().chars.clone())
+        } else {
+            LoxValue::Object(value.to_string())
+        }
+    }
+}
+
+impl From<bool> for LoxValue {
+    fn from(b: bool) -> Self {
+        LoxValue::Bool(b)
+    }
+}
+
+impl From<f64> for LoxValue {
+    fn from(n: f64) -> Self {
+        LoxValue::Number(n)
+    }
+}
+
+impl From<&str> for LoxValue {
+    fn from(s: &str) -> Self {
+        LoxValue::String(s.to_string())
+    }
+}
+
+impl From<String> for LoxValue {
+    fn from(s: String) -> Self {
+        LoxValue::String(s)
+    }
+}
+
+impl fmt::Display for LoxValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxValue::Nil => write!(f, "nil"),
+            LoxValue::Bool(b) => write!(f, "{}", b),
+            LoxValue::Number(n) => write!(f, "{}", n),
+            LoxValue::String(s) | LoxValue::Object(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// What a host function accepts in one parameter. The VM checks arguments
+/// before the function runs, so it can match on the variant without a fallback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    Any,
+    Bool,
+    Number,
+    String,
+}
+
+impl ArgType {
+    pub fn accepts(&self, value: Value) -> bool {
+        match self {
+            ArgType::Any => true,
+            ArgType::Bool => value.is_bool(),
+            ArgType::Number => value.is_number(),
+            ArgType::String => value.is_string(),
+        }
+    }
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgType::Any => write!(f, "any value"),
+            ArgType::Bool => write!(f, "a boolean"),
+            ArgType::Number => write!(f, "a number"),
+            ArgType::String => write!(f, "a string"),
+        }
+    }
+}
+
+/// A Rust function callable from Lox, registered with [Vm::define_function].
+/// An `Err` becomes a Lox runtime error with that message.
+///
+/// [Vm::define_function]: crate::vm::Vm::define_function
+pub type HostFn = Box<dyn FnMut(&[LoxValue]) -> Result<LoxValue, String>>;
+
+/// One line of a runtime error's stack trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLine {
+    pub line: i32,
+    /// 1-based, of the token the failing instruction was compiled from.
+    pub column: i32,
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}:{}] in {}()", self.line, self.column, name),
+            None => write!(f, "[line {}:{}] in script", self.line, self.column),
+        }
+    }
+}
+
+/// Bounds on a single run, for scripts that can't be trusted. `None` is
+/// unlimited. See [Vm::set_limits].
+///
+/// [Vm::set_limits]: crate::vm::Vm::set_limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions executed since the run started.
+    pub max_instructions: Option<u64>,
+    /// Bytes the heap holds after a collection.
+    pub max_heap_bytes: Option<usize>,
+    /// Frames on the call stack, counting the script's own. The VM's fixed
+    /// limit of 64 still applies.
+    pub max_call_depth: Option<usize>,
+    /// Checked about every thousand instructions, so a run can go a little past it.
+    pub deadline: Option<Instant>,
+}
+
+/// Which of the [Limits] a run hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    HeapBytes,
+    CallDepth,
+    Deadline,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Instructions => write!(f, "Instruction limit exceeded."),
+            Limit::HeapBytes => write!(f, "Heap limit exceeded."),
+            Limit::CallDepth => write!(f, "Call depth limit exceeded."),
+            Limit::Deadline => write!(f, "Deadline passed."),
+        }
+    }
+}
+
+/// Why running Lox failed. Displays as the CLI reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    /// Every error the compiler found, in source order.
+    Compile(Vec<CompileError>),
+    /// The stack trace runs innermost frame first, and is empty for errors
+    /// raised outside any Lox code, like calling an undefined global.
+    Runtime {
+        message: String,
+        trace: Vec<TraceLine>,
+    },
+    /// The run stopped at an instruction boundary with its frames intact;
+    /// it can be [resumed](crate::vm::Vm::resume).
+    LimitExceeded(Limit),
+    /// The user quit the [debugger](crate::debugger::Debugger) mid-run.
+    Stopped,
+}
+
+impl LoxError {
+    pub(crate) fn runtime(message: &str) -> Self {
+        LoxError::Runtime {
+            message: message.to_string(),
+            trace: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxError::Compile(errors) => {
+                let lines: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            LoxError::Runtime { message, trace } => {
+                write!(f, "{}", message)?;
+                for line in trace {
+                    write!(f, "\n{}", line)?;
+                }
+                Ok(())
+            }
+            LoxError::LimitExceeded(limit) => write!(f, "{}", limit),
+            LoxError::Stopped => write!(f, "Stopped by the user."),
+        }
+    }
+}
+
+impl Error for LoxError {}
@@ -1,7 +1,9 @@
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::Error as IoError;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
@@ -9,10 +11,19 @@ use std::process::exit;
 
 pub mod chunk;
 pub mod compiler;
+pub mod debugger;
+pub mod host;
+pub mod memory;
+pub mod object;
+pub mod optimizer;
+pub mod profiler;
 pub mod scanner;
+pub mod serialize;
+pub mod table;
 pub mod value;
 pub mod vm;
 
+use scanner::{Scanner, TokenType};
 use vm::InterpretErrorCode;
 
 #[allow(non_camel_case_types)]
@@ -20,69 +31,258 @@ use vm::InterpretErrorCode;
 #[derive(Debug, Clone, Copy)]
 pub enum OpCode {
     OP_CONSTANT,
+    /// Like OP_CONSTANT, with a 24-bit big-endian operand.
+    OP_CONSTANT_LONG,
+    OP_NIL,
+    OP_TRUE,
+    OP_FALSE,
+    OP_POP,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_GET_GLOBAL,
+    OP_GET_GLOBAL_LONG,
+    OP_DEFINE_GLOBAL,
+    OP_DEFINE_GLOBAL_LONG,
+    OP_SET_GLOBAL,
+    OP_SET_GLOBAL_LONG,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_GET_PROPERTY,
+    OP_GET_PROPERTY_LONG,
+    OP_SET_PROPERTY,
+    OP_SET_PROPERTY_LONG,
+    OP_GET_SUPER,
+    OP_GET_SUPER_LONG,
+    OP_EQUAL,
+    OP_GREATER,
+    OP_LESS,
     OP_ADD,
     OP_SUBTRACT,
     OP_MULTIPLY,
     OP_DIVIDE,
+    OP_NOT,
     OP_NEGATE,
+    OP_PRINT,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_INVOKE,
+    OP_INVOKE_LONG,
+    OP_SUPER_INVOKE,
+    OP_SUPER_INVOKE_LONG,
+    OP_CLOSURE,
+    OP_CLOSURE_LONG,
+    OP_CLOSE_UPVALUE,
     OP_RETURN,
+    OP_CLASS,
+    OP_CLASS_LONG,
+    OP_INHERIT,
+    OP_METHOD,
+    OP_METHOD_LONG,
+
+    // Superinstructions: the compiler fuses these from common sequences.
+    /// OP_GET_LOCAL of slots 0 to 3, without an operand byte.
+    OP_GET_LOCAL_0,
+    OP_GET_LOCAL_1,
+    OP_GET_LOCAL_2,
+    OP_GET_LOCAL_3,
+    /// OP_CONSTANT of a number followed by OP_ADD.
+    OP_ADD_CONSTANT,
+    /// OP_LESS followed by OP_JUMP_IF_FALSE, with the jump's operand.
+    OP_LESS_JUMP_IF_FALSE,
+    /// `local = local + 1` for the local in the operand slot, leaving the
+    /// new value on the stack like OP_SET_LOCAL.
+    OP_INCREMENT_LOCAL,
 
     UNKNOWN,
 }
 
 impl From<u8> for OpCode {
     fn from(from: u8) -> Self {
-        use self::OpCode::*;
-        match from {
-            0 => OP_CONSTANT,
-            1 => OP_ADD,
-            2 => OP_SUBTRACT,
-            3 => OP_MULTIPLY,
-            4 => OP_DIVIDE,
-            5 => OP_NEGATE,
-            6 => OP_RETURN,
-            _ => UNKNOWN,
+        if from < OpCode::UNKNOWN as u8 {
+            // OpCode is repr(u8) with implicit discriminants, so every byte
+            // below UNKNOWN names a variant.
+            unsafe { std::mem::transmute::<u8, OpCode>(from) }
+        } else {
+            OpCode::UNKNOWN
         }
     }
 }
 
-pub fn repl() -> Result<(), IoError> {
+/// Reads entries from stdin and runs them on one VM until EOF, so
+/// definitions carry over. An entry continues over several lines while it
+/// has unclosed braces, parentheses or strings.
+pub fn repl(options: &Options) -> Result<(), IoError> {
     let mut reader = BufReader::new(std::io::stdin());
+    let mut vm = vm::Vm::init_vm();
+    vm.set_optimize(options.optimize);
+    if let Some(dest) = &options.trace {
+        vm.set_trace(Some(trace_output(dest)?));
+    }
+    if options.profile {
+        vm.set_profiler(Some(profiler::Profiler::new()));
+    }
 
+    let mut entry = String::new();
     loop {
-        print!("> ");
+        print!("{}", if entry.is_empty() { "> " } else { "... " });
         std::io::stdout().flush()?;
-        let mut line = String::new();
-        let len = reader.read_line(&mut line)?;
+        let len = reader.read_line(&mut entry)?;
         if len == 0 {
             println!();
+            return write_profile(&mut vm, options, None);
+        }
+        if entry.trim().is_empty() {
+            entry.clear();
+            continue;
+        }
+        if is_incomplete(&entry) {
+            continue;
+        }
+
+        let source = entry.trim_end().to_string();
+        entry.clear();
+        if let Err(e) = vm.run_repl_entry(source) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Whether `source` stops inside a block, a parenthesized expression or a string.
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LEFT_BRACE | TokenType::LEFT_PAREN => depth += 1,
+            TokenType::RIGHT_BRACE | TokenType::RIGHT_PAREN => depth -= 1,
+            TokenType::ERROR if token.string == "Unterminated string." => return true,
+            TokenType::EOF => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+/// Command-line switches shared by the run, compile and disassemble modes.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Run each compiled chunk through the optimizer (`-O`).
+    pub optimize: bool,
+    /// Where to log every instruction executed (`--trace` or RLOX_TRACE).
+    pub trace: Option<String>,
+    /// Run the script under the [debugger](debugger::Debugger) (`--debug`).
+    pub debug: bool,
+    /// Report where the run spent its time on exit (`--profile`).
+    pub profile: bool,
+    /// Where to write the profile's call stacks for flamegraph tools
+    /// (`--profile-stacks`). Implies `profile`.
+    pub profile_stacks: Option<String>,
+}
+
+fn source_string(bytes: Vec<u8>) -> Result<String, IoError> {
+    String::from_utf8(bytes)
+        .map_err(|_| IoError::new(ErrorKind::InvalidData, "script is not valid UTF-8"))
+}
+
+/// Compiles the script at `input` and writes its bytecode to `output`.
+pub fn compile_file(input: &Path, output: &Path, options: &Options) -> Result<(), IoError> {
+    let mut f = File::open(input)?;
+    let mut bytes: Vec<u8> = Vec::new();
+    f.read_to_end(&mut bytes)?;
+    let code = source_string(bytes)?;
+    match vm::Vm::compile_bytecode(code, options.optimize) {
+        Ok(bytecode) => File::create(output)?.write_all(&bytecode),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(65)
         }
-        match vm::Vm::interpret(line) {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("{}", e);
-            }
+    }
+}
+
+/// Prints the bytecode of a Lox script, or of a .loxc file, without running it.
+pub fn disassemble_file(path: &Path, options: &Options) -> Result<(), IoError> {
+    let mut f = File::open(path)?;
+    let mut bytes: Vec<u8> = Vec::new();
+    f.read_to_end(&mut bytes)?;
+    let result = if serialize::is_bytecode(&bytes) {
+        vm::Vm::disassemble_bytecode(&bytes)
+    } else {
+        vm::Vm::disassemble(source_string(bytes)?, options.optimize)
+    };
+    match result {
+        Ok(text) => std::io::stdout().write_all(text.as_bytes()),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(65)
         }
     }
 }
 
-pub fn run_file(path: &Path) -> Result<(), IoError> {
+/// Opens a trace destination: `-` for stdout, anything else is a file to create.
+fn trace_output(dest: &str) -> Result<Box<dyn Write>, IoError> {
+    if dest == "-" {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(dest)?)))
+    }
+}
+
+/// Prints the profiler's report to stderr and writes its stacks file, if
+/// `options` asked for them.
+fn write_profile(vm: &mut vm::Vm, options: &Options, source: Option<&str>) -> Result<(), IoError> {
+    let profiler = match vm.take_profiler() {
+        Some(profiler) => profiler,
+        None => return Ok(()),
+    };
+    profiler.report(&mut std::io::stderr(), source)?;
+    if let Some(dest) = &options.profile_stacks {
+        let mut out = BufWriter::new(File::create(dest)?);
+        profiler.write_stacks(&mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Runs a Lox script, or a .loxc file compiled from one. Errors in the
+/// script are reported on stderr, and returned so the caller can pick an
+/// exit code.
+pub fn run_file(path: &Path, options: &Options) -> Result<vm::InterpretResult, IoError> {
     let mut f = File::open(path)?;
     let mut bytes: Vec<u8> = Vec::new();
     f.read_to_end(&mut bytes)?;
-    let code = String::from_utf8(bytes).expect("invalid string");
-    match vm::Vm::interpret(code) {
-        Ok(_) => Ok(()),
-        Err(e) => match e {
-            InterpretErrorCode::CompileError => {
-                eprintln!("{}", e);
-                exit(65)
-            }
-            InterpretErrorCode::RuntimeError => {
-                eprintln!("{}", e);
-                exit(66)
-            }
-            _ => Ok(()),
-        },
+    let mut vm = vm::Vm::init_vm();
+    vm.set_optimize(options.optimize);
+    if let Some(dest) = &options.trace {
+        vm.set_trace(Some(trace_output(dest)?));
+    }
+    if options.profile {
+        vm.set_profiler(Some(profiler::Profiler::new()));
+    }
+    let mut source = None;
+    let result = if serialize::is_bytecode(&bytes) {
+        if options.debug {
+            eprintln!("Only Lox source can be debugged; .loxc files have no variable names.");
+            exit(64);
+        }
+        vm.run_bytecode(&bytes)
+    } else {
+        let text = source_string(bytes)?;
+        if options.debug {
+            let input = Box::new(BufReader::new(std::io::stdin()));
+            let output = Box::new(std::io::stdout());
+            let path = path.display().to_string();
+            let debugger = debugger::Debugger::new(&path, &text, input, output);
+            vm.set_debugger(Some(debugger));
+        }
+        source = Some(text.clone());
+        vm.run_source(text)
+    };
+    if !matches!(result, Err(InterpretErrorCode::CompileError)) {
+        write_profile(&mut vm, options, source.as_deref())?;
     }
+    // Flush a buffered trace file before the caller's exit() skips its destructor.
+    drop(vm);
+    Ok(result)
 }
@@ -1,23 +1,94 @@
 extern crate rlox_iii;
-use std::env::args;
+use std::env::{args, var};
 use std::error::Error;
 use std::path::PathBuf;
 use std::process::exit;
 
+use rlox_iii::vm::InterpretErrorCode;
 use rlox_iii::*;
 
+fn usage() -> ! {
+    println!("Usage: rlox [options] [script]");
+    println!("       rlox [options] compile [script] -o [output]");
+    println!("       rlox [options] --disassemble [script]");
+    println!();
+    println!("Options:");
+    println!("  -O                      optimize the compiled bytecode");
+    println!("  --trace [output|-]      log every instruction executed");
+    println!("  --debug                 step through the script in a debugger");
+    println!("  --profile               report instructions and time per function and line");
+    println!("  --profile-stacks [file] also write the call stacks for flamegraph tools");
+    exit(64);
+}
+
+/// Exits after a failed run, with sysexits.h codes: 65 for bad input and 70
+/// for an error while running.
+fn fail(error: InterpretErrorCode) -> ! {
+    let code = match error {
+        InterpretErrorCode::CompileError => 65,
+        InterpretErrorCode::RuntimeError | InterpretErrorCode::LimitExceeded => 70,
+        // Quitting the debugger is like interrupting the program.
+        InterpretErrorCode::Stopped => exit(130),
+        InterpretErrorCode::Ok => exit(0),
+    };
+    eprintln!("{}", error);
+    exit(code)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    match args().len() {
-        3..=usize::MAX => {
-            println!("Usage: rlox [script]");
-            exit(64);
+    let mut args: Vec<String> = args().collect();
+    let mut options = Options {
+        // --trace wins over RLOX_TRACE.
+        trace: var("RLOX_TRACE").ok().filter(|dest| !dest.is_empty()),
+        ..Options::default()
+    };
+    while args.len() > 1 {
+        match args[1].as_str() {
+            "-O" => {
+                options.optimize = true;
+                args.remove(1);
+            }
+            "--trace" if args.len() > 2 => {
+                options.trace = Some(args.remove(2));
+                args.remove(1);
+            }
+            "--trace" => usage(),
+            "--debug" => {
+                options.debug = true;
+                args.remove(1);
+            }
+            "--profile" => {
+                options.profile = true;
+                args.remove(1);
+            }
+            "--profile-stacks" if args.len() > 2 => {
+                options.profile = true;
+                options.profile_stacks = Some(args.remove(2));
+                args.remove(1);
+            }
+            "--profile-stacks" => usage(),
+            _ => break,
+        }
+    }
+
+    match args.len() {
+        // The debugger only runs scripts, and its pauses would swamp a profile.
+        _ if options.debug && (args.len() != 2 || options.profile) => usage(),
+        5 if args[1] == "compile" && args[3] == "-o" => {
+            compile_file(&PathBuf::from(&args[2]), &PathBuf::from(&args[4]), &options)?;
+        }
+        3 if args[1] == "--disassemble" => {
+            disassemble_file(&PathBuf::from(&args[2]), &options)?;
         }
+        3..=usize::MAX => usage(),
         2 => {
-            let path = PathBuf::from(args().nth(1).unwrap());
-            run_file(&path)?;
+            let path = PathBuf::from(&args[1]);
+            if let Err(e) = run_file(&path, &options)? {
+                fail(e);
+            }
         }
         _ => {
-            repl()?;
+            repl(&options)?;
         }
     }
     Ok(())
@@ -0,0 +1,246 @@
+use std::mem;
+
+use crate::object::*;
+use crate::table::{hash_string, Table};
+use crate::value::Value;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// Anything holding objects the collector must keep alive:
+/// the VM's stack, frames and globals, or the compiler's functions in progress.
+pub trait GcRoots {
+    fn mark_roots(&self, heap: &mut Heap);
+}
+
+/// Owns every object allocated by the compiler and the VM,
+/// and reclaims them with a tracing mark-and-sweep collector.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    /// Interned strings. The table does not keep its strings alive.
+    strings: Table,
+    gray_stack: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            strings: Table::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+        }
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// May run a collection before allocating. References held by `kind`
+    /// itself are kept alive, so callers only need to root everything else.
+    pub fn alloc(&mut self, kind: ObjKind, roots: &dyn GcRoots) -> ObjRef {
+        let obj = Obj::new(kind);
+        let size = obj.size();
+
+        #[cfg(feature = "stress_gc")]
+        self.collect_garbage(roots, Some(&obj));
+        #[cfg(not(feature = "stress_gc"))]
+        if self.bytes_allocated + size > self.next_gc {
+            self.collect_garbage(roots, Some(&obj));
+        }
+
+        let boxed = Box::new(obj);
+        let obj = unsafe { ObjRef::from_raw(Box::into_raw(boxed)) };
+        self.objects.push(obj);
+        self.bytes_allocated += size;
+
+        #[cfg(feature = "log_gc")]
+        println!("{:p} allocate {} for {}", obj.as_ptr(), size, obj.type_name());
+
+        obj
+    }
+
+    /// The interned string with these chars, if there is one. Never allocates.
+    pub fn find_string(&self, chars: &str) -> Option<ObjRef> {
+        self.strings.find_string(chars, hash_string(chars))
+    }
+
+    /// Used as ObjString* copyString(const char* chars, int length);
+    pub fn copy_string(&mut self, chars: &str, roots: &dyn GcRoots) -> ObjRef {
+        let hash = hash_string(chars);
+        if let Some(interned) = self.strings.find_string(chars, hash) {
+            return interned;
+        }
+        self.allocate_string(chars.to_string(), hash, roots)
+    }
+
+    /// Used as ObjString* takeString(char* chars, int length);
+    pub fn take_string(&mut self, chars: String, roots: &dyn GcRoots) -> ObjRef {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return interned;
+        }
+        self.allocate_string(chars, hash, roots)
+    }
+
+    fn allocate_string(&mut self, chars: String, hash: u32, roots: &dyn GcRoots) -> ObjRef {
+        let string = self.alloc(ObjKind::String(ObjString { chars, hash }), roots);
+        self.strings.set(string, Value::nil());
+        string
+    }
+
+    pub fn collect_garbage(&mut self, roots: &dyn GcRoots, pending: Option<&Obj>) {
+        #[cfg(feature = "log_gc")]
+        println!("-- gc begin");
+        #[cfg(feature = "log_gc")]
+        let before = self.bytes_allocated;
+
+        roots.mark_roots(self);
+        if let Some(pending) = pending {
+            self.blacken(pending);
+        }
+        self.trace_references();
+        self.remove_white_strings();
+        self.sweep();
+
+        self.next_gc = usize::max(
+            self.bytes_allocated * GC_HEAP_GROW_FACTOR,
+            GC_INITIAL_THRESHOLD,
+        );
+
+        #[cfg(feature = "log_gc")]
+        {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if value.is_obj() {
+            self.mark_object(value.as_obj());
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        if obj.is_marked.get() {
+            return;
+        }
+
+        #[cfg(feature = "log_gc")]
+        println!("{:p} mark {}", obj.as_ptr(), obj);
+
+        obj.is_marked.set(true);
+        self.gray_stack.push(obj);
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_object(key);
+            self.mark_value(value);
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken(&obj);
+        }
+    }
+
+    fn blacken(&mut self, obj: &Obj) {
+        #[cfg(feature = "log_gc")]
+        println!("{:p} blacken {}", obj, obj);
+
+        match &obj.kind {
+            ObjKind::String(_) | ObjKind::Native(_) => {}
+            ObjKind::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for constant in function.chunk.constants.iter() {
+                    self.mark_value(*constant);
+                }
+            }
+            ObjKind::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in closure.upvalues.iter() {
+                    self.mark_object(*upvalue);
+                }
+            }
+            ObjKind::Upvalue(upvalue) => {
+                if let UpvalueState::Closed(value) = upvalue.state.get() {
+                    self.mark_value(value);
+                }
+            }
+            ObjKind::Class(class) => {
+                self.mark_object(class.name);
+                self.mark_table(&class.methods.borrow());
+            }
+            ObjKind::Instance(instance) => {
+                self.mark_object(instance.class);
+                self.mark_table(&instance.fields.borrow());
+            }
+            ObjKind::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+        }
+    }
+
+    /// The intern table holds weak references: drop strings nobody else marked.
+    fn remove_white_strings(&mut self) {
+        self.strings.remove_white();
+    }
+
+    fn sweep(&mut self) {
+        let mut live = 0;
+        let objects = mem::take(&mut self.objects);
+        for obj in objects {
+            if obj.is_marked.get() {
+                obj.is_marked.set(false);
+                live += obj.size();
+                self.objects.push(obj);
+            } else {
+                Self::free_object(obj);
+            }
+        }
+        self.bytes_allocated = live;
+    }
+
+    fn free_object(obj: ObjRef) {
+        #[cfg(feature = "log_gc")]
+        println!("{:p} free type {}", obj.as_ptr(), obj.type_name());
+
+        unsafe { drop(Box::from_raw(obj.as_ptr())) };
+    }
+
+    /// Used as void freeObjects();
+    pub fn free_objects(&mut self) {
+        self.strings = Table::new();
+        for obj in self.objects.drain(..) {
+            Self::free_object(obj);
+        }
+        self.bytes_allocated = 0;
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.free_objects();
+    }
+}
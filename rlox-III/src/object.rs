@@ -0,0 +1,317 @@
+use crate::chunk::{Chunk, LineRun};
+use crate::host::{ArgType, HostFn};
+use crate::table::{Entry, Table};
+use crate::value::Value;
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// Every heap-allocated value shares this header, like struct Obj in clox.
+/// Objects are owned by [crate::memory::Heap] and referred to via [ObjRef].
+#[derive(Debug)]
+pub struct Obj {
+    pub is_marked: Cell<bool>,
+    pub kind: ObjKind,
+}
+
+#[derive(Debug)]
+pub enum ObjKind {
+    String(ObjString),
+    Function(ObjFunction),
+    Native(ObjNative),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// None for the top-level script.
+    pub name: Option<ObjRef>,
+    /// Names of the locals, for the debugger. Not saved in .loxc files.
+    pub locals: Vec<LocalName>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            locals: Vec::new(),
+        }
+    }
+}
+
+/// A local variable lives in stack `slot` of its frame while the code between
+/// offsets `start` and `end` runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalName {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub type NativeFn = fn(&[Value]) -> Value;
+
+pub enum NativeFunction {
+    /// Part of the VM, like `clock`.
+    Builtin(NativeFn),
+    /// Registered by the embedding program, which gets one argument per
+    /// entry of `params`, each of that type.
+    Host {
+        params: Vec<ArgType>,
+        function: RefCell<HostFn>,
+    },
+}
+
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFunction,
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjNative({})", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A captured variable: it points into the VM stack while the variable is
+/// still live, and owns the value once its slot has been popped.
+#[derive(Debug, Clone, Copy)]
+pub enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjUpvalue {
+    pub state: Cell<UpvalueState>,
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> Self {
+        Self {
+            state: Cell::new(UpvalueState::Open(slot)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: RefCell<Table>,
+}
+
+impl ObjClass {
+    pub fn new(name: ObjRef) -> Self {
+        Self {
+            name,
+            methods: RefCell::new(Table::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: RefCell<Table>,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: RefCell::new(Table::new()),
+        }
+    }
+}
+
+/// A method closure paired with the instance it was accessed from.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl Obj {
+    pub fn new(kind: ObjKind) -> Self {
+        Self {
+            is_marked: Cell::new(false),
+            kind,
+        }
+    }
+
+    /// Approximate number of bytes this object owns, for GC accounting.
+    pub fn size(&self) -> usize {
+        let payload = match &self.kind {
+            ObjKind::String(s) => s.chars.capacity(),
+            ObjKind::Function(f) => {
+                f.chunk.code.capacity()
+                    + f.chunk.lines.capacity() * mem::size_of::<LineRun>()
+                    + f.chunk.columns.capacity()
+                    + f.chunk.constants.capacity() * mem::size_of::<Value>()
+            }
+            ObjKind::Closure(c) => c.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            ObjKind::Class(c) => c.methods.borrow().capacity() * mem::size_of::<Entry>(),
+            ObjKind::Instance(i) => i.fields.borrow().capacity() * mem::size_of::<Entry>(),
+            ObjKind::Native(_) | ObjKind::Upvalue(_) | ObjKind::BoundMethod(_) => 0,
+        };
+        mem::size_of::<Obj>() + payload
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match &self.kind {
+            ObjKind::String(_) => "string",
+            ObjKind::Function(_) => "function",
+            ObjKind::Native(_) => "native",
+            ObjKind::Closure(_) => "closure",
+            ObjKind::Upvalue(_) => "upvalue",
+            ObjKind::Class(_) => "class",
+            ObjKind::Instance(_) => "instance",
+            ObjKind::BoundMethod(_) => "bound method",
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(self.kind, ObjKind::String(_))
+    }
+
+    pub fn as_string(&self) -> &ObjString {
+        match &self.kind {
+            ObjKind::String(s) => s,
+            _ => panic!("Not a string object"),
+        }
+    }
+
+    pub fn as_function(&self) -> &ObjFunction {
+        match &self.kind {
+            ObjKind::Function(f) => f,
+            _ => panic!("Not a function object"),
+        }
+    }
+
+    pub fn as_closure(&self) -> &ObjClosure {
+        match &self.kind {
+            ObjKind::Closure(c) => c,
+            _ => panic!("Not a closure object"),
+        }
+    }
+
+    pub fn as_upvalue(&self) -> &ObjUpvalue {
+        match &self.kind {
+            ObjKind::Upvalue(u) => u,
+            _ => panic!("Not an upvalue object"),
+        }
+    }
+
+    pub fn is_class(&self) -> bool {
+        matches!(self.kind, ObjKind::Class(_))
+    }
+
+    pub fn as_class(&self) -> &ObjClass {
+        match &self.kind {
+            ObjKind::Class(c) => c,
+            _ => panic!("Not a class object"),
+        }
+    }
+
+    pub fn is_instance(&self) -> bool {
+        matches!(self.kind, ObjKind::Instance(_))
+    }
+
+    pub fn as_instance(&self) -> &ObjInstance {
+        match &self.kind {
+            ObjKind::Instance(i) => i,
+            _ => panic!("Not an instance object"),
+        }
+    }
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(s) => write!(f, "{}", s.chars),
+            ObjKind::Function(function) => write!(f, "{}", function),
+            ObjKind::Native(native) => write!(f, "#<Function: {}>", native.name),
+            ObjKind::Closure(closure) => write!(f, "{}", closure.function),
+            ObjKind::Upvalue(_) => write!(f, "upvalue"),
+            ObjKind::Class(class) => write!(f, "#<Class: {}>", class.name),
+            ObjKind::Instance(instance) => {
+                write!(f, "#<Instance of {}>", instance.class.as_class().name)
+            }
+            ObjKind::BoundMethod(bound) => write!(f, "{}", bound.method),
+        }
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "#<Function: {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// A non-owning pointer to an object living in the heap.
+/// Comparing two refs compares identity, not contents.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    /// # Safety
+    /// `ptr` must come from Box::into_raw and stay alive while this ref is in use.
+    pub unsafe fn from_raw(ptr: *mut Obj) -> Self {
+        Self(NonNull::new_unchecked(ptr))
+    }
+
+    pub fn as_ptr(self) -> *mut Obj {
+        self.0.as_ptr()
+    }
+}
+
+impl Deref for ObjRef {
+    type Target = Obj;
+
+    fn deref(&self) -> &Obj {
+        // Objects are only freed by the heap once nothing refers to them.
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
@@ -0,0 +1,495 @@
+//! An optional pass over each finished chunk, run by the compiler under `-O`.
+//!
+//! The chunk is decoded into a list of instructions with jumps pointing at
+//! instruction indices, rewritten until nothing changes, and encoded again.
+//! Every rewrite keeps or shrinks the code, so re-encoded jumps still fit in
+//! 16 bits. Instructions that survive keep their own line and column; a
+//! folded constant takes the position of the first instruction it replaces.
+//!
+//! The rewrites:
+//!
+//! * Arithmetic, comparison and `!` on literal operands become one literal,
+//!   including an OP_ADD_CONSTANT applied to a literal.
+//! * A literal or local read followed by `OP_POP` is dropped.
+//! * `OP_NOT OP_NOT` is dropped where only the truthiness of the result can be
+//!   observed, as in an `if` or `while` condition, or where the operand is
+//!   already a boolean.
+//! * Jumps to the next instruction are dropped, and jumps that land on an
+//!   unconditional jump go straight to its target.
+//!
+//! Nothing here allocates, so string concatenation is left to the VM. The
+//! debugger's local variable ranges move with the code they cover.
+
+use crate::chunk::Chunk;
+use crate::object::{ObjFunction, ObjKind};
+use crate::value::Value;
+use crate::OpCode;
+use crate::OpCode::*;
+
+use std::collections::HashMap;
+
+/// Optimizes `function`'s chunk in place. Nested functions are optimized as
+/// the compiler finishes them, so this doesn't recurse into constants.
+pub fn optimize(function: &mut ObjFunction) {
+    let chunk = std::mem::take(&mut function.chunk);
+    let mut optimizer = Optimizer::new(chunk);
+    while optimizer.sweep() {}
+    let offsets = optimizer.offsets();
+    for local in function.locals.iter_mut() {
+        local.start = offsets[optimizer.index_at[local.start]];
+        local.end = offsets[optimizer.index_at[local.end]];
+    }
+    function.chunk = optimizer.encode(&offsets);
+}
+
+struct Instruction {
+    opcode: OpCode,
+    /// Operand bytes; empty for jumps, which keep their target in `target`.
+    operands: Vec<u8>,
+    /// Index of the instruction a jump lands on.
+    target: usize,
+    line: i32,
+    column: i32,
+    live: bool,
+}
+
+impl Instruction {
+    fn is_jump(&self) -> bool {
+        matches!(
+            self.opcode,
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP | OP_LESS_JUMP_IF_FALSE
+        )
+    }
+
+    fn size(&self) -> usize {
+        if self.is_jump() {
+            3
+        } else {
+            1 + self.operands.len()
+        }
+    }
+}
+
+struct Optimizer {
+    code: Vec<Instruction>,
+    /// Index into `code` of the instruction at each original offset.
+    index_at: Vec<usize>,
+    /// Whether a live jump may land on each instruction.
+    is_target: Vec<bool>,
+    constants: Vec<Value>,
+    /// Constant table index of each number, by its bits, so folds reuse slots.
+    numbers: HashMap<u64, usize>,
+}
+
+impl Optimizer {
+    fn new(chunk: Chunk) -> Self {
+        let mut columns = Vec::with_capacity(chunk.code.len());
+        for (length, column) in chunk.column_runs() {
+            columns.extend(std::iter::repeat_n(column, length));
+        }
+
+        let mut code = Vec::new();
+        let mut index_at = vec![usize::MAX; chunk.code.len() + 1];
+        let mut jump_offsets = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let opcode: OpCode = chunk.code[offset].into();
+            let length = operand_length(&chunk, offset);
+            let operands = &chunk.code[offset + 1..offset + 1 + length];
+            index_at[offset] = code.len();
+
+            let next = offset + 1 + length;
+            let mut instruction = Instruction {
+                opcode,
+                operands: operands.to_vec(),
+                target: 0,
+                line: chunk.get_line(offset),
+                column: columns[offset],
+                live: true,
+            };
+            if instruction.is_jump() {
+                let distance = ((operands[0] as usize) << 8) | operands[1] as usize;
+                let target = match opcode {
+                    OP_LOOP => next - distance,
+                    _ => next + distance,
+                };
+                jump_offsets.push((code.len(), target));
+                instruction.operands.clear();
+            }
+            code.push(instruction);
+            offset = next;
+        }
+        index_at[offset] = code.len();
+
+        let mut is_target = vec![false; code.len() + 1];
+        for (index, target) in jump_offsets {
+            code[index].target = index_at[target];
+            is_target[index_at[target]] = true;
+        }
+
+        let mut numbers = HashMap::new();
+        for (index, constant) in chunk.constants.iter().enumerate() {
+            if constant.is_number() {
+                numbers
+                    .entry(constant.as_number().to_bits())
+                    .or_insert(index);
+            }
+        }
+
+        Self {
+            code,
+            index_at,
+            is_target,
+            constants: chunk.constants,
+            numbers,
+        }
+    }
+
+    /// Applies every rewrite once down the chunk; true if anything changed.
+    fn sweep(&mut self) -> bool {
+        let mut changed = false;
+        for i in 0..self.code.len() {
+            if self.code[i].live {
+                changed |= self.peephole(i);
+            }
+        }
+        changed
+    }
+
+    fn peephole(&mut self, i: usize) -> bool {
+        if self.code[i].is_jump() {
+            return self.jump(i);
+        }
+
+        let j = match self.next(i) {
+            Some(j) if !self.is_target[j] => j,
+            _ => return false,
+        };
+
+        if let Some(a) = self.literal(i) {
+            match self.code[j].opcode {
+                OP_POP => {
+                    self.remove(i);
+                    self.remove(j);
+                    return true;
+                }
+                OP_NOT => return self.fold(i, &[j], Value::boolean(a.is_falsey())),
+                OP_NEGATE if a.is_number() => {
+                    return self.fold(i, &[j], Value::number(-a.as_number()))
+                }
+                OP_ADD_CONSTANT if a.is_number() => {
+                    let b = self.constants[self.code[j].operands[0] as usize];
+                    return self.fold(i, &[j], Value::number(a.as_number() + b.as_number()));
+                }
+                _ => {}
+            }
+
+            if let Some(b) = self.literal(j) {
+                let k = match self.next(j) {
+                    Some(k) if !self.is_target[k] => k,
+                    _ => return false,
+                };
+                if let Some(value) = binary(self.code[k].opcode, a, b) {
+                    return self.fold(i, &[j, k], value);
+                }
+            }
+            return false;
+        }
+
+        match (self.code[i].opcode, self.code[j].opcode) {
+            (OP_GET_LOCAL, OP_POP)
+            | (OP_GET_LOCAL_0, OP_POP)
+            | (OP_GET_LOCAL_1, OP_POP)
+            | (OP_GET_LOCAL_2, OP_POP)
+            | (OP_GET_LOCAL_3, OP_POP)
+            | (OP_GET_UPVALUE, OP_POP) => {
+                self.remove(i);
+                self.remove(j);
+                true
+            }
+            (OP_NOT, OP_NOT) if self.only_truthiness(i, j) => {
+                self.remove(i);
+                self.remove(j);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `!!x` at `i`, `j` can be replaced by `x`: either the next
+    /// instruction only tests it, or `x` is already a boolean. A test only
+    /// counts when both ways out of it pop the condition, as `if` and `while`
+    /// do; `and` and `or` leave it on the stack as their result.
+    fn only_truthiness(&self, i: usize, j: usize) -> bool {
+        if let Some(k) = self.next(j) {
+            if matches!(self.code[k].opcode, OP_JUMP_IF_FALSE)
+                && !self.is_target[k]
+                && self.pops(self.next(k))
+                && self.pops(Some(self.resolve(self.code[k].target)))
+            {
+                return true;
+            }
+        }
+        if self.is_target[i] {
+            return false;
+        }
+        match self.previous(i) {
+            Some(p) => matches!(
+                self.code[p].opcode,
+                OP_EQUAL | OP_GREATER | OP_LESS | OP_NOT | OP_TRUE | OP_FALSE
+            ),
+            None => false,
+        }
+    }
+
+    fn pops(&self, i: Option<usize>) -> bool {
+        matches!(i, Some(i) if i < self.code.len() && matches!(self.code[i].opcode, OP_POP))
+    }
+
+    fn jump(&mut self, i: usize) -> bool {
+        let target = self.resolve(self.code[i].target);
+        let compares = matches!(self.code[i].opcode, OP_LESS_JUMP_IF_FALSE);
+        if Some(target) == self.next(i) && !compares {
+            // Falling through does the same thing, and OP_JUMP_IF_FALSE
+            // leaves the condition on the stack either way.
+            self.remove(i);
+            return true;
+        }
+
+        if target == self.code.len() || !matches!(self.code[target].opcode, OP_JUMP | OP_LOOP) {
+            return false;
+        }
+        let last = self.resolve(self.code[target].target);
+        if last == target {
+            return false;
+        }
+
+        let conditional = matches!(
+            self.code[i].opcode,
+            OP_JUMP_IF_FALSE | OP_LESS_JUMP_IF_FALSE
+        );
+        let forward = last > i;
+        if conditional && !forward {
+            return false;
+        }
+        let distance = if forward {
+            self.bytes_between(i + 1, last)
+        } else {
+            self.bytes_between(last, i + 1)
+        };
+        if distance > u16::MAX as usize {
+            return false;
+        }
+
+        let instruction = &mut self.code[i];
+        if !conditional {
+            instruction.opcode = if forward { OP_JUMP } else { OP_LOOP };
+        }
+        instruction.target = last;
+        self.is_target[last] = true;
+        true
+    }
+
+    /// Replaces `i` with `value` and drops `rest`, unless that would grow the code.
+    fn fold(&mut self, i: usize, rest: &[usize], value: Value) -> bool {
+        let (opcode, operands) = if value.is_bool() {
+            let opcode = if value.as_bool() { OP_TRUE } else { OP_FALSE };
+            (opcode, Vec::new())
+        } else {
+            let bits = value.as_number().to_bits();
+            let index = match self.numbers.get(&bits) {
+                Some(&index) => index,
+                None => self.constants.len(),
+            };
+            if index <= u8::MAX as usize {
+                (OP_CONSTANT, vec![index as u8])
+            } else {
+                (
+                    OP_CONSTANT_LONG,
+                    vec![(index >> 16) as u8, (index >> 8) as u8, index as u8],
+                )
+            }
+        };
+
+        let before: usize = std::iter::once(i)
+            .chain(rest.iter().copied())
+            .map(|index| self.code[index].size())
+            .sum();
+        if 1 + operands.len() > before {
+            return false;
+        }
+
+        if value.is_number() && !self.numbers.contains_key(&value.as_number().to_bits()) {
+            self.numbers
+                .insert(value.as_number().to_bits(), self.constants.len());
+            self.constants.push(value);
+        }
+        self.code[i].opcode = opcode;
+        self.code[i].operands = operands;
+        for &index in rest {
+            self.remove(index);
+        }
+        true
+    }
+
+    /// The value `i` pushes, if it's a literal.
+    fn literal(&self, i: usize) -> Option<Value> {
+        let instruction = &self.code[i];
+        match instruction.opcode {
+            OP_NIL => Some(Value::nil()),
+            OP_TRUE => Some(Value::boolean(true)),
+            OP_FALSE => Some(Value::boolean(false)),
+            OP_CONSTANT => Some(self.constants[instruction.operands[0] as usize]),
+            OP_CONSTANT_LONG => {
+                let bytes = &instruction.operands;
+                let index =
+                    ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize;
+                Some(self.constants[index])
+            }
+            _ => None,
+        }
+    }
+
+    /// Drops `i`. Jumps that landed on it now land on whatever follows.
+    fn remove(&mut self, i: usize) {
+        self.code[i].live = false;
+        if self.is_target[i] {
+            let next = self.next(i).unwrap_or(self.code.len());
+            self.is_target[next] = true;
+        }
+    }
+
+    fn next(&self, i: usize) -> Option<usize> {
+        (i + 1..self.code.len()).find(|&index| self.code[index].live)
+    }
+
+    fn previous(&self, i: usize) -> Option<usize> {
+        (0..i).rev().find(|&index| self.code[index].live)
+    }
+
+    /// The first live instruction at or after `i`.
+    fn resolve(&self, i: usize) -> usize {
+        (i..self.code.len())
+            .find(|&index| self.code[index].live)
+            .unwrap_or(self.code.len())
+    }
+
+    fn bytes_between(&self, from: usize, to: usize) -> usize {
+        self.code[from..to]
+            .iter()
+            .filter(|instruction| instruction.live)
+            .map(Instruction::size)
+            .sum()
+    }
+
+    /// Where each instruction lands in the encoded chunk. A removed one maps
+    /// to the next instruction that survives, and the last entry is the end.
+    fn offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.code.len() + 1);
+        let mut offset = 0;
+        for instruction in self.code.iter() {
+            offsets.push(offset);
+            if instruction.live {
+                offset += instruction.size();
+            }
+        }
+        offsets.push(offset);
+        offsets
+    }
+
+    fn encode(self, offsets: &[usize]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for (i, instruction) in self.code.iter().enumerate() {
+            if !instruction.live {
+                continue;
+            }
+            let (line, column) = (instruction.line, instruction.column);
+            chunk.write(instruction.opcode as u8, line, column);
+
+            if instruction.is_jump() {
+                let target = offsets[self.resolve(instruction.target)];
+                let next = offsets[i] + 3;
+                let distance = match instruction.opcode {
+                    OP_LOOP => next - target,
+                    _ => target - next,
+                };
+                debug_assert!(distance <= u16::MAX as usize);
+                chunk.write((distance >> 8) as u8, line, column);
+                chunk.write(distance as u8, line, column);
+            } else {
+                for &byte in instruction.operands.iter() {
+                    chunk.write(byte, line, column);
+                }
+            }
+        }
+        chunk.constants = self.constants;
+        chunk
+    }
+}
+
+/// The result of applying a binary opcode to two literals, when the VM
+/// would produce it without an error or an allocation.
+fn binary(opcode: OpCode, a: Value, b: Value) -> Option<Value> {
+    if let OP_EQUAL = opcode {
+        return Some(Value::boolean(a == b));
+    }
+    if !a.is_number() || !b.is_number() {
+        return None;
+    }
+    let (a, b) = (a.as_number(), b.as_number());
+    match opcode {
+        OP_ADD => Some(Value::number(a + b)),
+        OP_SUBTRACT => Some(Value::number(a - b)),
+        OP_MULTIPLY => Some(Value::number(a * b)),
+        OP_DIVIDE => Some(Value::number(a / b)),
+        OP_GREATER => Some(Value::boolean(a > b)),
+        OP_LESS => Some(Value::boolean(a < b)),
+        _ => None,
+    }
+}
+
+/// An OP_CLOSURE's operand bytes: the `width` of the function's constant
+/// index, then a pair per upvalue.
+fn closure_length(chunk: &Chunk, index: usize, width: usize) -> usize {
+    match &chunk.constants[index].as_obj().kind {
+        ObjKind::Function(function) => width + 2 * function.upvalue_count,
+        _ => unreachable!("OP_CLOSURE names a function"),
+    }
+}
+
+/// The number of operand bytes after the opcode at `offset`.
+fn operand_length(chunk: &Chunk, offset: usize) -> usize {
+    match chunk.code[offset].into() {
+        OP_NIL | OP_TRUE | OP_FALSE | OP_POP | OP_EQUAL | OP_GREATER | OP_LESS | OP_ADD
+        | OP_SUBTRACT | OP_MULTIPLY | OP_DIVIDE | OP_NOT | OP_NEGATE | OP_PRINT
+        | OP_CLOSE_UPVALUE | OP_RETURN | OP_INHERIT | OP_GET_LOCAL_0 | OP_GET_LOCAL_1
+        | OP_GET_LOCAL_2 | OP_GET_LOCAL_3 => 0,
+        OP_GET_LOCAL | OP_SET_LOCAL | OP_GET_UPVALUE | OP_SET_UPVALUE | OP_CALL | OP_CONSTANT
+        | OP_GET_GLOBAL | OP_DEFINE_GLOBAL | OP_SET_GLOBAL | OP_GET_PROPERTY | OP_SET_PROPERTY
+        | OP_GET_SUPER | OP_CLASS | OP_METHOD | OP_ADD_CONSTANT | OP_INCREMENT_LOCAL => 1,
+        OP_INVOKE
+        | OP_SUPER_INVOKE
+        | OP_JUMP
+        | OP_JUMP_IF_FALSE
+        | OP_LOOP
+        | OP_LESS_JUMP_IF_FALSE => 2,
+        OP_CONSTANT_LONG
+        | OP_GET_GLOBAL_LONG
+        | OP_DEFINE_GLOBAL_LONG
+        | OP_SET_GLOBAL_LONG
+        | OP_GET_PROPERTY_LONG
+        | OP_SET_PROPERTY_LONG
+        | OP_GET_SUPER_LONG
+        | OP_CLASS_LONG
+        | OP_METHOD_LONG => 3,
+        OP_INVOKE_LONG | OP_SUPER_INVOKE_LONG => 4,
+        OP_CLOSURE => closure_length(chunk, chunk.code[offset + 1] as usize, 1),
+        OP_CLOSURE_LONG => {
+            let bytes = &chunk.code[offset + 1..offset + 4];
+            let index =
+                ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize;
+            closure_length(chunk, index, 3)
+        }
+        UNKNOWN => unreachable!("the compiler only emits known opcodes"),
+    }
+}
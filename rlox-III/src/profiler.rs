@@ -0,0 +1,343 @@
+//! An instrumenting profiler for `--profile`. The VM hands it control before
+//! every instruction; it counts instructions and wall time per function and
+//! per source line, and keeps a call tree for flamegraphs.
+//!
+//! Each instruction is charged the time until the next one starts, so a
+//! native call's time goes to the line that made it. The profiler's own
+//! bookkeeping is in those times too, which inflates cheap instructions a
+//! little; instruction counts are exact.
+
+use crate::object::ObjFunction;
+use crate::vm::Vm;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// How many of the hottest lines the report lists.
+const LINES_SHOWN: usize = 20;
+
+/// The root of the call tree, above every script.
+const ROOT: usize = 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Stats {
+    instructions: u64,
+    time: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, other: Stats) {
+        self.instructions += other.instructions;
+        self.time += other.time;
+    }
+}
+
+/// One call path: a function as called from its parent's node.
+#[derive(Debug)]
+struct Node {
+    function: usize,
+    parent: usize,
+    children: HashMap<usize, usize>,
+    /// Spent in this function itself, not in its callees.
+    own: Stats,
+}
+
+#[derive(Debug)]
+pub struct Profiler {
+    /// The functions seen, indexed by `Node::function`, by name and the
+    /// line their code starts on. Scripts share one entry.
+    functions: Vec<(Option<String>, i32)>,
+    function_ids: HashMap<(Option<String>, i32), usize>,
+    nodes: Vec<Node>,
+    lines: HashMap<(usize, i32), Stats>,
+    /// The running frames, outermost first, as each one's function and node.
+    path: Vec<(*const ObjFunction, usize)>,
+    /// Where the last instruction ran, and what it has been charged so far.
+    /// Flushed into `nodes` and `lines` when the line or node changes.
+    current: Option<(usize, i32)>,
+    pending: Stats,
+    /// When the last instruction started, while a run is going.
+    last: Option<Instant>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            nodes: vec![Node {
+                function: 0,
+                parent: ROOT,
+                children: HashMap::new(),
+                own: Stats::default(),
+            }],
+            lines: HashMap::new(),
+            path: Vec::new(),
+            current: None,
+            pending: Stats::default(),
+            last: None,
+        }
+    }
+
+    /// Called by the VM before each instruction.
+    pub(crate) fn before_instruction(&mut self, vm: &Vm) {
+        let now = Instant::now();
+        if let Some(last) = self.last {
+            self.pending.time += now - last;
+        }
+        self.last = Some(now);
+
+        let (depth, line) = vm.position();
+        let node = self.node(vm, depth);
+        if self.current != Some((node, line)) {
+            self.flush();
+            self.current = Some((node, line));
+        }
+        self.pending.instructions += 1;
+    }
+
+    /// Called by the VM when a run ends, so the time until the next one
+    /// isn't charged to its last instruction. The path goes too: once the
+    /// frames are gone, their functions may be collected and their addresses
+    /// reused.
+    pub(crate) fn stop(&mut self) {
+        if let Some(last) = self.last.take() {
+            self.pending.time += last.elapsed();
+        }
+        self.flush();
+        self.current = None;
+        self.path.clear();
+    }
+
+    fn flush(&mut self) {
+        if let Some((node, line)) = self.current {
+            let pending = std::mem::take(&mut self.pending);
+            self.nodes[node].own.add(pending);
+            let function = self.nodes[node].function;
+            self.lines.entry((function, line)).or_default().add(pending);
+        }
+    }
+
+    /// The call tree node of the innermost of `depth` frames. Only calls and
+    /// returns change it, so the path is rebuilt from the first frame whose
+    /// function differs.
+    fn node(&mut self, vm: &Vm, depth: usize) -> usize {
+        if self.path.len() == depth {
+            if let Some(&(function, node)) = self.path.last() {
+                if ptr_eq(function, vm.frame_function(depth - 1)) {
+                    return node;
+                }
+            }
+        }
+
+        let same = (0..depth.min(self.path.len()))
+            .take_while(|&i| ptr_eq(self.path[i].0, vm.frame_function(i)))
+            .count();
+        self.path.truncate(same);
+        for i in same..depth {
+            let function = vm.frame_function(i);
+            let id = self.function_id(function);
+            let parent = self.path.last().map_or(ROOT, |&(_, node)| node);
+            let node = match self.nodes[parent].children.get(&id) {
+                Some(&node) => node,
+                None => {
+                    let node = self.nodes.len();
+                    self.nodes.push(Node {
+                        function: id,
+                        parent,
+                        children: HashMap::new(),
+                        own: Stats::default(),
+                    });
+                    self.nodes[parent].children.insert(id, node);
+                    node
+                }
+            };
+            self.path.push((function, node));
+        }
+        self.path.last().map_or(ROOT, |&(_, node)| node)
+    }
+
+    /// Functions are told apart by name and the line their code starts on,
+    /// so methods of different classes with the same name stay separate.
+    fn function_id(&mut self, function: &ObjFunction) -> usize {
+        let key = match function.name {
+            Some(name) => (Some(name.to_string()), function.chunk.get_line(0)),
+            None => (None, 0),
+        };
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+        self.functions.push(key.clone());
+        self.function_ids.insert(key, self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+
+    /// What the reports call each function: its name, with the line its code
+    /// starts on when another function has the same name.
+    fn labels(&self) -> Vec<String> {
+        let mut counts: HashMap<&Option<String>, usize> = HashMap::new();
+        for (name, _) in self.functions.iter() {
+            *counts.entry(name).or_default() += 1;
+        }
+        self.functions
+            .iter()
+            .map(|(name, line)| match name {
+                Some(label) if counts[name] > 1 => format!("{}:{}", label, line),
+                Some(label) => label.clone(),
+                None => "script".to_string(),
+            })
+            .collect()
+    }
+
+    /// Each node's stats including its callees'. Children come after their
+    /// parents in `nodes`, so one backwards pass adds them up.
+    fn inclusive(&self) -> Vec<Stats> {
+        let mut totals: Vec<Stats> = self.nodes.iter().map(|node| node.own).collect();
+        for node in (1..self.nodes.len()).rev() {
+            let total = totals[node];
+            totals[self.nodes[node].parent].add(total);
+        }
+        totals
+    }
+
+    fn ancestors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(self.nodes[node].parent), move |&node| {
+            Some(self.nodes[node].parent)
+        })
+        .take_while(|&node| node != ROOT)
+    }
+
+    /// Writes the per-function and per-line tables, most time first. With
+    /// the script's `source`, the line table shows each line's text.
+    pub fn report(&self, out: &mut dyn Write, source: Option<&str>) -> io::Result<()> {
+        let inclusive = self.inclusive();
+        let total = inclusive[ROOT];
+        let mut functions = vec![(Stats::default(), Stats::default()); self.functions.len()];
+        for (node, n) in self.nodes.iter().enumerate().skip(1) {
+            let function = n.function;
+            functions[function].0.add(n.own);
+            // A recursive call's time is already in the outermost call's total.
+            if self
+                .ancestors(node)
+                .all(|up| self.nodes[up].function != function)
+            {
+                functions[function].1.add(inclusive[node]);
+            }
+        }
+        let labels = self.labels();
+        let width = labels.iter().map(|label| label.len()).max().unwrap_or(0);
+        let percent = |time: Duration| {
+            if total.time.as_nanos() == 0 {
+                0.0
+            } else {
+                100.0 * time.as_secs_f64() / total.time.as_secs_f64()
+            }
+        };
+
+        writeln!(
+            out,
+            "Profile: {} instructions in {:.3} ms",
+            total.instructions,
+            millis(total.time)
+        )?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>12} {:>9} {:>6} {:>9} {:>6}  function",
+            "instructions", "self ms", "%", "total ms", "%"
+        )?;
+        let mut order: Vec<usize> = (0..functions.len()).collect();
+        order.sort_by_key(|&f| (std::cmp::Reverse(functions[f].0.time), f));
+        for f in order {
+            let (own, all) = functions[f];
+            writeln!(
+                out,
+                "{:>12} {:>9.3} {:>6.1} {:>9.3} {:>6.1}  {}",
+                own.instructions,
+                millis(own.time),
+                percent(own.time),
+                millis(all.time),
+                percent(all.time),
+                labels[f]
+            )?;
+        }
+
+        let source: Vec<&str> = source.map_or(Vec::new(), |source| source.lines().collect());
+        let mut lines: Vec<(&(usize, i32), &Stats)> = self.lines.iter().collect();
+        lines.sort_by_key(|&(&(function, line), stats)| {
+            (std::cmp::Reverse(stats.time), line, function)
+        });
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>12} {:>9} {:>6} {:>5}  {:<width$}  source",
+            "instructions",
+            "ms",
+            "%",
+            "line",
+            "function",
+            width = width
+        )?;
+        for (&(function, line), stats) in lines.iter().take(LINES_SHOWN) {
+            let text = (line as usize)
+                .checked_sub(1)
+                .and_then(|index| source.get(index))
+                .map_or("", |text| text.trim());
+            writeln!(
+                out,
+                "{:>12} {:>9.3} {:>6.1} {:>5}  {:<width$}  {}",
+                stats.instructions,
+                millis(stats.time),
+                percent(stats.time),
+                line,
+                labels[function],
+                text,
+                width = width
+            )?;
+        }
+        if lines.len() > LINES_SHOWN {
+            writeln!(out, "({} more lines)", lines.len() - LINES_SHOWN)?;
+        }
+        Ok(())
+    }
+
+    /// Writes one line per call path, `script;outer;inner count`, in the
+    /// collapsed-stack format flamegraph tools read. Counts are instructions,
+    /// so profiles of the same program compare exactly.
+    pub fn write_stacks(&self, out: &mut dyn Write) -> io::Result<()> {
+        let labels = self.labels();
+        let mut stacks: Vec<(String, u64)> = Vec::new();
+        for (node, n) in self.nodes.iter().enumerate().skip(1) {
+            if n.own.instructions == 0 {
+                continue;
+            }
+            let mut frames: Vec<&str> = self
+                .ancestors(node)
+                .map(|up| labels[self.nodes[up].function].as_str())
+                .collect();
+            frames.reverse();
+            frames.push(&labels[n.function]);
+            stacks.push((frames.join(";"), n.own.instructions));
+        }
+        stacks.sort();
+        for (stack, count) in stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn ptr_eq(a: *const ObjFunction, b: &ObjFunction) -> bool {
+    std::ptr::eq(a, b)
+}
+
+fn millis(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
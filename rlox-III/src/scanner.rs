@@ -1,18 +1,29 @@
+use std::marker::PhantomData;
+
 #[derive(Debug)]
 pub struct Scanner<'src> {
-    source: &'src str,
+    /// The source decoded once up front; `start` and `current` index into this.
+    chars: Vec<char>,
     start: usize,
     current: usize,
     line: i64,
+    /// Index of the first char on the current line.
+    line_start: usize,
+    /// 1-based column of the token being scanned.
+    column: i64,
+    source: PhantomData<&'src str>,
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
         Self {
-            source,
+            chars: source.chars().collect(),
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            column: 1,
+            source: PhantomData,
         }
     }
 
@@ -20,6 +31,7 @@ impl<'src> Scanner<'src> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.column = (self.current - self.line_start) as i64 + 1;
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
         }
@@ -110,7 +122,7 @@ impl<'src> Scanner<'src> {
     }
 
     fn is_at_end(&mut self) -> bool {
-        self.current == self.source.len()
+        self.current == self.chars.len()
     }
 
     fn advance(&mut self) -> char {
@@ -131,11 +143,14 @@ impl<'src> Scanner<'src> {
     }
 
     fn peek(&mut self) -> char {
+        if self.is_at_end() {
+            return '\0';
+        }
         self.chars()[self.current]
     }
 
     fn peeknext(&mut self) -> char {
-        if self.is_at_end() {
+        if self.current + 1 >= self.chars.len() {
             return '\0';
         }
         self.chars()[self.current + 1]
@@ -154,16 +169,13 @@ impl<'src> Scanner<'src> {
                 '\n' => {
                     self.line += 1;
                     self.advance();
+                    self.line_start = self.current;
                 }
-                '/' => {
-                    if self.peeknext() == '/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
-                    };
+                '/' if self.peeknext() == '/' => {
+                    // A comment goes until the end of the line.
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
                 }
                 _ => {
                     return;
@@ -180,7 +192,10 @@ impl<'src> Scanner<'src> {
         tt: TokenType,
     ) -> TokenType {
         if self.current - self.start == start + length
-            && &self.source[(self.start + start)..(self.start + start + length)] == rest
+            && self.chars[(self.start + start)..(self.start + start + length)]
+                .iter()
+                .copied()
+                .eq(rest.chars())
         {
             tt
         } else {
@@ -192,6 +207,7 @@ impl<'src> Scanner<'src> {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
                 self.line += 1;
+                self.line_start = self.current + 1;
             }
             self.advance();
         }
@@ -246,10 +262,10 @@ impl<'src> Scanner<'src> {
                             return self.check_keyword(2, 3, "lse", TokenType::FALSE);
                         }
                         'o' => {
-                            return self.check_keyword(1, 4, "r", TokenType::FOR);
+                            return self.check_keyword(2, 1, "r", TokenType::FOR);
                         }
                         'u' => {
-                            return self.check_keyword(1, 3, "n", TokenType::FUN);
+                            return self.check_keyword(2, 1, "n", TokenType::FUN);
                         }
                         _ => {}
                     }
@@ -277,10 +293,10 @@ impl<'src> Scanner<'src> {
                 if self.current - self.start > 1 {
                     match self.chars()[self.start + 1] {
                         'h' => {
-                            return self.check_keyword(2, 3, "is", TokenType::THIS);
+                            return self.check_keyword(2, 2, "is", TokenType::THIS);
                         }
                         'r' => {
-                            return self.check_keyword(1, 4, "ue", TokenType::TRUE);
+                            return self.check_keyword(2, 2, "ue", TokenType::TRUE);
                         }
                         _ => {}
                     }
@@ -299,12 +315,13 @@ impl<'src> Scanner<'src> {
     }
 
     fn make_token(&mut self, token_type: TokenType) -> Token {
-        let string = self.source[self.start..self.current].to_owned();
+        let string = self.chars[self.start..self.current].iter().collect();
         Token {
             token_type,
             start: self.start,
             length: self.current - self.start,
             line: self.line,
+            column: self.column,
             string,
         }
     }
@@ -315,21 +332,22 @@ impl<'src> Scanner<'src> {
             start: 0,
             length: message.len(),
             line: self.line,
+            column: self.column,
             string: message.to_string(),
         }
     }
 
-    fn chars(&self) -> Vec<char> {
-        self.source.chars().collect::<Vec<char>>()
+    fn chars(&self) -> &[char] {
+        &self.chars
     }
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 fn is_alphanumeric(c: char) -> bool {
@@ -343,6 +361,7 @@ pub struct Token {
     pub length: usize,
     pub string: String,
     pub line: i64,
+    pub column: i64,
 }
 
 impl Token {
@@ -353,6 +372,7 @@ impl Token {
             length: 0,
             string: "".to_string(),
             line: 0,
+            column: 0,
         }
     }
 
@@ -0,0 +1,658 @@
+//! The .loxc format: a compiled script saved so it can run without the source.
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! file     := "LOXC" version:u16 function   (arity and upvalue_count 0)
+//! function := name:(0 | 1 string) arity:u32 upvalue_count:u32 chunk
+//! chunk    := code:bytes lines:(count:u32 (offset:u32 line:i32)*) columns:bytes
+//!             constants:(count:u32 constant*)
+//! constant := 0 | 1 | 2                (nil, false, true)
+//!           | 3 f64 | 4 string | 5 function
+//!                                      (the only NaN is f64::NAN)
+//! string   := bytes, valid UTF-8
+//! bytes    := length:u32 u8*
+//! ```
+//!
+//! Loading checks the bytecode before the VM ever sees it: every instruction
+//! is known, its operands fit in the code, constant operands have the right
+//! type, jumps land on an instruction, the code can't run off its end, and no
+//! path pops or reads below the frame's slots.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::chunk::{Chunk, LineRun};
+use crate::memory::{GcRoots, Heap};
+use crate::object::{ObjFunction, ObjKind, ObjRef};
+use crate::value::Value;
+use crate::OpCode;
+use crate::OpCode::*;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bump whenever the layout above or the opcode numbering changes.
+pub const VERSION: u16 = 3;
+
+/// Deeper nesting than this is rejected rather than risking the native stack.
+const MAX_NESTING: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a .loxc file."),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported .loxc version {} (expected {}).",
+                version, VERSION
+            ),
+            LoadError::Truncated => write!(f, "Unexpected end of .loxc file."),
+            LoadError::Corrupt(message) => write!(f, "Corrupt .loxc file: {}", message),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serializes a compiled script, along with every function nested in it.
+pub fn serialize(function: &ObjFunction) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+    writer.function(function);
+    writer.bytes
+}
+
+/// Loads a script saved by [serialize]. Every object is allocated in `heap`,
+/// with `roots` keeping the rest of the VM alive if a collection runs meanwhile.
+pub fn deserialize(
+    bytes: &[u8],
+    heap: &mut Heap,
+    roots: &dyn GcRoots,
+) -> Result<ObjRef, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+        heap,
+        roots,
+        loaded: Vec::new(),
+    };
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let function = reader.function(0)?;
+    if reader.pos != bytes.len() {
+        return Err(corrupt("trailing bytes after the script"));
+    }
+    Ok(reader.alloc(ObjKind::Function(function)))
+}
+
+fn corrupt(message: &str) -> LoadError {
+    LoadError::Corrupt(message.to_string())
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn function(&mut self, function: &ObjFunction) {
+        match function.name {
+            Some(name) => {
+                self.u8(1);
+                self.bytes(name.as_string().chars.as_bytes());
+            }
+            None => self.u8(0),
+        }
+        self.u32(function.arity);
+        self.u32(function.upvalue_count);
+        self.chunk(&function.chunk);
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.bytes(&chunk.code);
+
+        self.u32(chunk.lines.len());
+        for run in chunk.lines.iter() {
+            self.u32(run.offset);
+            self.i32(run.line);
+        }
+        self.bytes(&chunk.columns);
+
+        self.u32(chunk.constants.len());
+        for constant in chunk.constants.iter() {
+            self.constant(*constant);
+        }
+    }
+
+    fn constant(&mut self, value: Value) {
+        if value.is_nil() {
+            self.u8(TAG_NIL);
+        } else if value.is_bool() {
+            self.u8(if value.as_bool() { TAG_TRUE } else { TAG_FALSE });
+        } else if value.is_number() {
+            self.u8(TAG_NUMBER);
+            let mut number = value.as_number();
+            if number.is_nan() {
+                number = f64::NAN;
+            }
+            self.bytes
+                .extend_from_slice(&number.to_bits().to_le_bytes());
+        } else {
+            let obj = value.as_obj();
+            match &obj.kind {
+                ObjKind::String(string) => {
+                    self.u8(TAG_STRING);
+                    self.bytes(string.chars.as_bytes());
+                }
+                ObjKind::Function(function) => {
+                    self.u8(TAG_FUNCTION);
+                    self.function(function);
+                }
+                // The compiler only ever puts numbers, strings and functions in a chunk.
+                _ => panic!("Can't serialize a {} constant", obj.type_name()),
+            }
+        }
+    }
+}
+
+/// The VM's roots plus every object loaded so far, which nothing else
+/// refers to until the script function is complete.
+struct LoaderRoots<'a> {
+    vm: &'a dyn GcRoots,
+    loaded: &'a [Value],
+}
+
+impl GcRoots for LoaderRoots<'_> {
+    fn mark_roots(&self, heap: &mut Heap) {
+        self.vm.mark_roots(heap);
+        for value in self.loaded.iter() {
+            heap.mark_value(*value);
+        }
+    }
+}
+
+struct Reader<'b, 'h> {
+    bytes: &'b [u8],
+    pos: usize,
+    heap: &'h mut Heap,
+    roots: &'h dyn GcRoots,
+    loaded: Vec<Value>,
+}
+
+impl<'b> Reader<'b, '_> {
+    fn take(&mut self, length: usize) -> Result<&'b [u8], LoadError> {
+        if self.bytes.len() - self.pos < length {
+            return Err(LoadError::Truncated);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn i32(&mut self) -> Result<i32, LoadError> {
+        Ok(self.u32()? as u32 as i32)
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        let mut bits = [0; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn bytes(&mut self) -> Result<&'b [u8], LoadError> {
+        let length = self.u32()?;
+        self.take(length)
+    }
+
+    /// A count of items that each take at least `item_size` bytes,
+    /// checked against what is left so a bad count can't allocate wildly.
+    fn count(&mut self, item_size: usize) -> Result<usize, LoadError> {
+        let count = self.u32()?;
+        if count > (self.bytes.len() - self.pos) / item_size {
+            return Err(LoadError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn string(&mut self) -> Result<ObjRef, LoadError> {
+        let bytes = self.bytes()?;
+        let chars = std::str::from_utf8(bytes).map_err(|_| corrupt("string is not UTF-8"))?;
+        let roots = LoaderRoots {
+            vm: self.roots,
+            loaded: &self.loaded,
+        };
+        let string = self.heap.copy_string(chars, &roots);
+        self.loaded.push(Value::obj(string));
+        Ok(string)
+    }
+
+    fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        let roots = LoaderRoots {
+            vm: self.roots,
+            loaded: &self.loaded,
+        };
+        let obj = self.heap.alloc(kind, &roots);
+        self.loaded.push(Value::obj(obj));
+        obj
+    }
+
+    fn function(&mut self, depth: usize) -> Result<ObjFunction, LoadError> {
+        if depth > MAX_NESTING {
+            return Err(corrupt("functions nested too deeply"));
+        }
+
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(corrupt("bad function name tag")),
+        };
+        let mut function = ObjFunction::new(name);
+        function.arity = self.u32()?;
+        function.upvalue_count = self.u32()?;
+        if function.arity > u8::MAX as usize || function.upvalue_count > u8::MAX as usize + 1 {
+            return Err(corrupt("function arity or upvalue count out of range"));
+        }
+        // The VM calls the script with no arguments and nothing to capture.
+        if depth == 0 && (function.arity != 0 || function.upvalue_count != 0) {
+            return Err(corrupt("the script takes arguments or captures upvalues"));
+        }
+        function.chunk = self.chunk(depth)?;
+
+        verify(&function)?;
+        Ok(function)
+    }
+
+    fn chunk(&mut self, depth: usize) -> Result<Chunk, LoadError> {
+        let code = self.bytes()?.to_vec();
+
+        let count = self.count(8)?;
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let offset = self.u32()?;
+            let line = self.i32()?;
+            lines.push(LineRun { offset, line });
+        }
+        let columns = self.bytes()?.to_vec();
+
+        let count = self.count(1)?;
+        let mut constants = Vec::with_capacity(count);
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::nil(),
+                TAG_FALSE => Value::boolean(false),
+                TAG_TRUE => Value::boolean(true),
+                TAG_NUMBER => {
+                    let number = self.f64()?;
+                    // Other NaNs could carry a value's tag or a pointer under nan_boxing.
+                    if number.is_nan() && number.to_bits() != f64::NAN.to_bits() {
+                        return Err(corrupt("non-canonical NaN constant"));
+                    }
+                    Value::number(number)
+                }
+                TAG_STRING => Value::obj(self.string()?),
+                TAG_FUNCTION => {
+                    let function = self.function(depth + 1)?;
+                    Value::obj(self.alloc(ObjKind::Function(function)))
+                }
+                _ => return Err(corrupt("bad constant tag")),
+            };
+            constants.push(constant);
+        }
+
+        let chunk = Chunk::from_parts(code, lines, columns, constants);
+        verify_positions(&chunk)?;
+        Ok(chunk)
+    }
+}
+
+/// The line table must cover every byte of code, so that error
+/// reporting and the disassembler never look past it.
+fn verify_positions(chunk: &Chunk) -> Result<(), LoadError> {
+    let code_len = chunk.code.len();
+
+    let mut next = 0;
+    for (i, run) in chunk.lines.iter().enumerate() {
+        if (i == 0 && run.offset != 0) || (i > 0 && run.offset < next) || run.offset >= code_len {
+            return Err(corrupt("line table out of order"));
+        }
+        next = run.offset + 1;
+    }
+    if chunk.lines.is_empty() && code_len > 0 {
+        return Err(corrupt("missing line table"));
+    }
+
+    let mut covered = 0;
+    let mut bytes = chunk.columns.iter();
+    while let Some(&length) = bytes.next() {
+        let mut shift = 0;
+        loop {
+            let byte = *bytes
+                .next()
+                .ok_or_else(|| corrupt("column table cut short"))?;
+            if shift > 28 {
+                return Err(corrupt("column out of range"));
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if length == 0 {
+            return Err(corrupt("empty column run"));
+        }
+        covered += length as usize;
+    }
+    if covered != code_len {
+        return Err(corrupt("column table doesn't match the code"));
+    }
+    Ok(())
+}
+
+/// Checks that `function`'s bytecode is safe to hand to the VM.
+fn verify(function: &ObjFunction) -> Result<(), LoadError> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+
+    let operand = |offset: usize, length: usize| -> Result<&[u8], LoadError> {
+        code.get(offset + 1..offset + 1 + length)
+            .ok_or_else(|| corrupt("instruction cut short"))
+    };
+    let constant = |index: usize| -> Result<Value, LoadError> {
+        chunk
+            .constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| corrupt("constant index out of range"))
+    };
+    let string = |index: usize| -> Result<(), LoadError> {
+        if constant(index)?.is_string() {
+            Ok(())
+        } else {
+            Err(corrupt("expected a string constant"))
+        }
+    };
+
+    // Decode every instruction in order, checking its operands.
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut starts = vec![false; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        starts[offset] = true;
+        let opcode = OpCode::from(code[offset]);
+        let mut jump = None;
+        let length = match opcode {
+            OP_NIL | OP_TRUE | OP_FALSE | OP_POP | OP_EQUAL | OP_GREATER | OP_LESS | OP_ADD
+            | OP_SUBTRACT | OP_MULTIPLY | OP_DIVIDE | OP_NOT | OP_NEGATE | OP_PRINT
+            | OP_CLOSE_UPVALUE | OP_RETURN | OP_INHERIT | OP_GET_LOCAL_0 | OP_GET_LOCAL_1
+            | OP_GET_LOCAL_2 | OP_GET_LOCAL_3 => 0,
+            OP_GET_LOCAL | OP_SET_LOCAL | OP_CALL | OP_INCREMENT_LOCAL => {
+                operand(offset, 1)?;
+                1
+            }
+            OP_GET_UPVALUE | OP_SET_UPVALUE => {
+                if operand(offset, 1)?[0] as usize >= function.upvalue_count {
+                    return Err(corrupt("upvalue index out of range"));
+                }
+                1
+            }
+            OP_CONSTANT => {
+                constant(operand(offset, 1)?[0] as usize)?;
+                1
+            }
+            OP_CONSTANT_LONG => {
+                constant(long_index(operand(offset, 3)?))?;
+                3
+            }
+            OP_ADD_CONSTANT => {
+                if !constant(operand(offset, 1)?[0] as usize)?.is_number() {
+                    return Err(corrupt("expected a number constant"));
+                }
+                1
+            }
+            OP_GET_GLOBAL | OP_DEFINE_GLOBAL | OP_SET_GLOBAL | OP_GET_PROPERTY
+            | OP_SET_PROPERTY | OP_GET_SUPER | OP_CLASS | OP_METHOD => {
+                string(operand(offset, 1)?[0] as usize)?;
+                1
+            }
+            OP_GET_GLOBAL_LONG
+            | OP_DEFINE_GLOBAL_LONG
+            | OP_SET_GLOBAL_LONG
+            | OP_GET_PROPERTY_LONG
+            | OP_SET_PROPERTY_LONG
+            | OP_GET_SUPER_LONG
+            | OP_CLASS_LONG
+            | OP_METHOD_LONG => {
+                string(long_index(operand(offset, 3)?))?;
+                3
+            }
+            OP_INVOKE | OP_SUPER_INVOKE => {
+                string(operand(offset, 2)?[0] as usize)?;
+                2
+            }
+            OP_INVOKE_LONG | OP_SUPER_INVOKE_LONG => {
+                string(long_index(operand(offset, 4)?))?;
+                4
+            }
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP | OP_LESS_JUMP_IF_FALSE => {
+                let bytes = operand(offset, 2)?;
+                let distance = ((bytes[0] as usize) << 8) | bytes[1] as usize;
+                let next = offset + 3;
+                jump = match opcode {
+                    OP_LOOP => next.checked_sub(distance),
+                    _ => Some(next + distance),
+                };
+                if jump.is_none() {
+                    return Err(corrupt("jump out of range"));
+                }
+                2
+            }
+            OP_CLOSURE | OP_CLOSURE_LONG => {
+                let width = closure_width(opcode);
+                let index = match width {
+                    1 => operand(offset, 1)?[0] as usize,
+                    _ => long_index(operand(offset, 3)?),
+                };
+                let value = constant(index)?;
+                if !value.is_obj() || !matches!(value.as_obj().kind, ObjKind::Function(_)) {
+                    return Err(corrupt("expected a function constant"));
+                }
+                let upvalue_count = value.as_obj().as_function().upvalue_count;
+                let upvalues = operand(offset + width, upvalue_count * 2)?;
+                for pair in upvalues.chunks(2) {
+                    let (is_local, index) = (pair[0], pair[1] as usize);
+                    if is_local > 1 || (is_local == 0 && index >= function.upvalue_count) {
+                        return Err(corrupt("bad closure upvalue"));
+                    }
+                }
+                width + upvalue_count * 2
+            }
+            UNKNOWN => return Err(corrupt("unknown opcode")),
+        };
+        instructions.push(Instruction {
+            offset,
+            opcode,
+            jump,
+        });
+        offset += 1 + length;
+    }
+
+    match instructions.last() {
+        Some(last) if matches!(last.opcode, OP_RETURN | OP_JUMP | OP_LOOP) => {}
+        _ => return Err(corrupt("code can run past its end")),
+    }
+    for instruction in instructions.iter() {
+        if let Some(target) = instruction.jump {
+            if target >= code.len() || !starts[target] {
+                return Err(corrupt("jump into the middle of an instruction"));
+            }
+        }
+    }
+
+    verify_stack(function, &instructions)
+}
+
+struct Instruction {
+    offset: usize,
+    opcode: OpCode,
+    jump: Option<usize>,
+}
+
+/// Follows every path through the code tracking how many values sit above
+/// the frame's slot zero, so nothing can pop or index below the frame.
+/// Paths that meet must agree on the height, as they always do in compiled code.
+fn verify_stack(function: &ObjFunction, instructions: &[Instruction]) -> Result<(), LoadError> {
+    let code = &function.chunk.code;
+    let index_of = |offset: usize| {
+        instructions
+            .binary_search_by_key(&offset, |instruction| instruction.offset)
+            .unwrap()
+    };
+
+    let mut heights: Vec<Option<usize>> = vec![None; instructions.len()];
+    // Slot zero holds the callee, followed by the arguments.
+    let mut pending = vec![(0, function.arity + 1)];
+    while let Some((index, height)) = pending.pop() {
+        match heights[index] {
+            Some(seen) if seen == height => continue,
+            Some(_) => return Err(corrupt("inconsistent stack height")),
+            None => heights[index] = Some(height),
+        }
+
+        let instruction = &instructions[index];
+        let byte = |n: usize| code[instruction.offset + n] as usize;
+        let (needs, pops, pushes) = match instruction.opcode {
+            OP_CONSTANT | OP_CONSTANT_LONG | OP_NIL | OP_TRUE | OP_FALSE | OP_GET_GLOBAL
+            | OP_GET_GLOBAL_LONG | OP_GET_UPVALUE | OP_CLASS | OP_CLASS_LONG => (0, 0, 1),
+            OP_GET_LOCAL | OP_INCREMENT_LOCAL => (byte(1) + 1, 0, 1),
+            OP_GET_LOCAL_0 => (1, 0, 1),
+            OP_GET_LOCAL_1 => (2, 0, 1),
+            OP_GET_LOCAL_2 => (3, 0, 1),
+            OP_GET_LOCAL_3 => (4, 0, 1),
+            OP_SET_LOCAL => (byte(1) + 1, 0, 0),
+            OP_POP | OP_DEFINE_GLOBAL | OP_DEFINE_GLOBAL_LONG | OP_PRINT | OP_CLOSE_UPVALUE => {
+                (1, 1, 0)
+            }
+            OP_SET_GLOBAL | OP_SET_GLOBAL_LONG | OP_SET_UPVALUE | OP_GET_PROPERTY
+            | OP_GET_PROPERTY_LONG | OP_NOT | OP_NEGATE | OP_JUMP_IF_FALSE | OP_ADD_CONSTANT => {
+                (1, 1, 1)
+            }
+            OP_SET_PROPERTY | OP_SET_PROPERTY_LONG | OP_GET_SUPER | OP_GET_SUPER_LONG
+            | OP_EQUAL | OP_GREATER | OP_LESS | OP_ADD | OP_SUBTRACT | OP_MULTIPLY
+            | OP_DIVIDE | OP_INHERIT | OP_METHOD | OP_METHOD_LONG | OP_LESS_JUMP_IF_FALSE => {
+                (2, 2, 1)
+            }
+            OP_CALL => (byte(1) + 1, byte(1) + 1, 1),
+            OP_INVOKE => (byte(2) + 1, byte(2) + 1, 1),
+            OP_INVOKE_LONG => (byte(4) + 1, byte(4) + 1, 1),
+            OP_SUPER_INVOKE => (byte(2) + 2, byte(2) + 2, 1),
+            OP_SUPER_INVOKE_LONG => (byte(4) + 2, byte(4) + 2, 1),
+            OP_CLOSURE | OP_CLOSURE_LONG => {
+                // Captured locals must already be on the stack.
+                let width = closure_width(instruction.opcode);
+                let index = match width {
+                    1 => byte(1),
+                    _ => (byte(1) << 16) | (byte(2) << 8) | byte(3),
+                };
+                let closed = function.chunk.constants[index].as_obj();
+                let upvalues = closed.as_function().upvalue_count;
+                let mut needs = 0;
+                for i in 0..upvalues {
+                    if byte(1 + width + i * 2) == 1 {
+                        needs = needs.max(byte(2 + width + i * 2) + 1);
+                    }
+                }
+                (needs, 0, 1)
+            }
+            OP_JUMP | OP_LOOP => (0, 0, 0),
+            OP_RETURN => (1, 1, 0),
+            UNKNOWN => unreachable!(),
+        };
+        // Slot zero itself is never popped: the VM discards it on return.
+        if height < needs.max(pops + 1) {
+            return Err(corrupt("stack underflow"));
+        }
+        let height = height - pops + pushes;
+
+        match instruction.opcode {
+            OP_RETURN => {}
+            OP_JUMP | OP_LOOP => pending.push((index_of(instruction.jump.unwrap()), height)),
+            _ => {
+                if let Some(target) = instruction.jump {
+                    pending.push((index_of(target), height));
+                }
+                if index + 1 < instructions.len() {
+                    pending.push((index + 1, height));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many bytes an OP_CLOSURE's constant index takes.
+fn closure_width(opcode: OpCode) -> usize {
+    match opcode {
+        OP_CLOSURE_LONG => 3,
+        _ => 1,
+    }
+}
+
+fn long_index(bytes: &[u8]) -> usize {
+    ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize
+}
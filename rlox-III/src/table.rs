@@ -0,0 +1,196 @@
+use crate::object::ObjRef;
+use crate::value::Value;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+/// Used as uint32_t hashString(const char* key, int length);
+/// 32-bit FNV-1a.
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Entry {
+    pub key: Option<ObjRef>,
+    pub value: Value,
+}
+
+impl Entry {
+    /// A deleted entry: no key, but a non-nil value so probing keeps going.
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !self.value.is_nil()
+    }
+}
+
+/// Open-addressing hash table with linear probing, keyed by interned strings.
+/// Since keys are interned, comparing them is a pointer compare.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    /// Live entries plus tombstones.
+    count: usize,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn find_entry(entries: &[Entry], key: ObjRef) -> usize {
+        let capacity = entries.len();
+        let mut index = key.as_string().hash as usize % capacity;
+        let mut tombstone = None;
+
+        loop {
+            let entry = &entries[index];
+            match entry.key {
+                None => {
+                    if entry.is_tombstone() {
+                        // We found a tombstone.
+                        if tombstone.is_none() {
+                            tombstone = Some(index);
+                        }
+                    } else {
+                        // Empty entry.
+                        return tombstone.unwrap_or(index);
+                    }
+                }
+                Some(k) if k == key => {
+                    // We found the key.
+                    return index;
+                }
+                _ => {}
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    pub fn get(&self, key: ObjRef) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[Self::find_entry(&self.entries, key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    /// Returns true when `key` was not in the table before.
+    pub fn set(&mut self, key: ObjRef, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.capacity() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.capacity() < 8 {
+                8
+            } else {
+                self.capacity() * 2
+            };
+            self.adjust_capacity(capacity);
+        }
+
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        if is_new_key && entry.value.is_nil() {
+            self.count += 1;
+        }
+
+        entry.key = Some(key);
+        entry.value = value;
+        is_new_key
+    }
+
+    pub fn delete(&mut self, key: ObjRef) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        // Find the entry.
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+
+        // Place a tombstone in the entry.
+        entry.key = None;
+        entry.value = Value::boolean(true);
+        true
+    }
+
+    /// Used as void tableAddAll(Table* from, Table* to);
+    pub fn add_all(&mut self, from: &Table) {
+        for (key, value) in from.iter() {
+            self.set(key, value);
+        }
+    }
+
+    /// Looks a string up by contents, for interning.
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let capacity = self.capacity();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                // Stop if we find an empty non-tombstone entry.
+                None if !entry.is_tombstone() => return None,
+                Some(key) => {
+                    let string = key.as_string();
+                    if string.hash == hash && string.chars == chars {
+                        // We found it.
+                        return Some(key);
+                    }
+                }
+                _ => {}
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    /// Deletes every entry whose key was not marked by the collector.
+    pub fn remove_white(&mut self) {
+        for i in 0..self.entries.len() {
+            if let Some(key) = self.entries[i].key {
+                if !key.is_marked.get() {
+                    self.delete(key);
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::default(); capacity];
+
+        self.count = 0;
+        for entry in self.entries.iter() {
+            if let Some(key) = entry.key {
+                let index = Self::find_entry(&entries, key);
+                entries[index] = *entry;
+                self.count += 1;
+            }
+        }
+
+        self.entries = entries;
+    }
+}
@@ -1,28 +1,236 @@
-use std::cell::Cell;
-
-#[derive(Clone)]
-pub struct Value(pub Cell<f64>);
+use crate::object::ObjRef;
 
 use std::fmt;
 
+#[cfg(not(feature = "nan_boxing"))]
+#[derive(Clone, Copy, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan_boxing"))]
 impl Value {
-    pub fn new(v: f64) -> Self {
-        Self(Cell::new(v))
+    /// Used as NIL_VAL
+    pub fn nil() -> Self {
+        Self::Nil
+    }
+
+    /// Used as BOOL_VAL(value)
+    pub fn boolean(v: bool) -> Self {
+        Self::Bool(v)
+    }
+
+    /// Used as NUMBER_VAL(value)
+    pub fn number(v: f64) -> Self {
+        Self::Number(v)
+    }
+
+    /// Used as OBJ_VAL(object)
+    pub fn obj(o: ObjRef) -> Self {
+        Self::Obj(o)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Self::Nil)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        matches!(self, Self::Bool(_))
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Self::Number(_))
+    }
+
+    pub fn is_obj(&self) -> bool {
+        matches!(self, Self::Obj(_))
+    }
+
+    pub fn as_bool(&self) -> bool {
+        match self {
+            Self::Bool(b) => *b,
+            _ => panic!("Not a bool: {:?}", self),
+        }
+    }
+
+    pub fn as_number(&self) -> f64 {
+        match self {
+            Self::Number(n) => *n,
+            _ => panic!("Not a number: {:?}", self),
+        }
+    }
+
+    pub fn as_obj(&self) -> ObjRef {
+        match self {
+            Self::Obj(o) => *o,
+            _ => panic!("Not an object: {:?}", self),
+        }
+    }
+}
+
+/// Used as bool valuesEqual(Value a, Value b);
+#[cfg(not(feature = "nan_boxing"))]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            // Strings are interned, so identity is equality for every object.
+            (Self::Obj(a), Self::Obj(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Every value packed into 64 bits, as clox does with NAN_BOXING.
+/// Numbers are stored as-is, but for NaNs, which all become one. Everything
+/// else hides in the payload of a quiet NaN: singletons as small tags, and
+/// objects as a pointer with the sign bit set.
+#[cfg(feature = "nan_boxing")]
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan_boxing")]
+mod bits {
+    pub const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    pub const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    pub const TAG_NIL: u64 = 1;
+    pub const TAG_FALSE: u64 = 2;
+    pub const TAG_TRUE: u64 = 3;
+
+    pub const NIL: u64 = QNAN | TAG_NIL;
+    pub const FALSE: u64 = QNAN | TAG_FALSE;
+    pub const TRUE: u64 = QNAN | TAG_TRUE;
+}
+
+#[cfg(feature = "nan_boxing")]
+impl Default for Value {
+    fn default() -> Self {
+        Self::nil()
     }
+}
 
-    pub fn map(&self, ops: impl Fn(f64) -> f64) -> Self {
-        let inner = self.0.get();
-        Self(Cell::new(ops(inner)))
+#[cfg(feature = "nan_boxing")]
+impl Value {
+    /// Used as NIL_VAL
+    pub fn nil() -> Self {
+        Self(bits::NIL)
     }
 
-    pub fn map2(pair: (&Value, &Value), ops: impl Fn((f64, f64)) -> f64) -> Value {
-        let (a, b) = pair;
-        Value(Cell::new(ops((a.0.get(), b.0.get()))))
+    /// Used as BOOL_VAL(value)
+    pub fn boolean(v: bool) -> Self {
+        Self(if v { bits::TRUE } else { bits::FALSE })
+    }
+
+    /// Used as NUMBER_VAL(value). Every NaN becomes the canonical one, whose
+    /// payload is clear, so no bit pattern can pass for a tag or a pointer.
+    pub fn number(v: f64) -> Self {
+        if v.is_nan() {
+            Self(f64::NAN.to_bits())
+        } else {
+            Self(v.to_bits())
+        }
+    }
+
+    /// Used as OBJ_VAL(object)
+    pub fn obj(o: ObjRef) -> Self {
+        Self(bits::SIGN_BIT | bits::QNAN | o.as_ptr() as u64)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == bits::NIL
+    }
+
+    pub fn is_bool(&self) -> bool {
+        // FALSE and TRUE differ only in the lowest bit.
+        (self.0 | 1) == bits::TRUE
+    }
+
+    pub fn is_number(&self) -> bool {
+        (self.0 & bits::QNAN) != bits::QNAN
+    }
+
+    pub fn is_obj(&self) -> bool {
+        (self.0 & (bits::QNAN | bits::SIGN_BIT)) == (bits::QNAN | bits::SIGN_BIT)
+    }
+
+    pub fn as_bool(&self) -> bool {
+        if !self.is_bool() {
+            panic!("Not a bool: {:?}", self);
+        }
+        self.0 == bits::TRUE
+    }
+
+    pub fn as_number(&self) -> f64 {
+        if !self.is_number() {
+            panic!("Not a number: {:?}", self);
+        }
+        f64::from_bits(self.0)
+    }
+
+    pub fn as_obj(&self) -> ObjRef {
+        if !self.is_obj() {
+            panic!("Not an object: {:?}", self);
+        }
+        let ptr = self.0 & !(bits::SIGN_BIT | bits::QNAN);
+        // Only heap pointers are ever boxed with both bits set.
+        unsafe { ObjRef::from_raw(ptr as *mut crate::object::Obj) }
+    }
+}
+
+/// Used as bool valuesEqual(Value a, Value b);
+#[cfg(feature = "nan_boxing")]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        // Compare numbers as floats so that NaN != NaN, like the tagged representation.
+        if self.is_number() && other.is_number() {
+            return self.as_number() == other.as_number();
+        }
+        self.0 == other.0
+    }
+}
+
+impl Value {
+    pub fn is_string(&self) -> bool {
+        self.is_obj() && self.as_obj().is_string()
+    }
+
+    pub fn is_class(&self) -> bool {
+        self.is_obj() && self.as_obj().is_class()
+    }
+
+    pub fn is_instance(&self) -> bool {
+        self.is_obj() && self.as_obj().is_instance()
+    }
+
+    /// nil and false are falsey, and every other value behaves like true.
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || (self.is_bool() && !self.as_bool())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_nil() {
+            write!(f, "nil")
+        } else if self.is_bool() {
+            write!(f, "{}", self.as_bool())
+        } else if self.is_number() {
+            write!(f, "{}", self.as_number())
+        } else {
+            write!(f, "{}", self.as_obj())
+        }
     }
 }
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0.get())
+        write!(f, "{}", self)
     }
 }
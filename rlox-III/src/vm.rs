@@ -1,132 +1,1507 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
 use crate::chunk::*;
 use crate::compiler;
+use crate::compiler::CompileOptions;
+use crate::debugger::Debugger;
+use crate::host::{ArgType, HostFn, Limit, Limits, LoxError, LoxValue, TraceLine};
+use crate::memory::{GcRoots, Heap};
+use crate::object::*;
+use crate::profiler::Profiler;
+use crate::serialize;
+use crate::table::Table;
 use crate::value::Value;
 use crate::OpCode;
 use crate::OpCode::*;
 
-const STACK_MAX: usize = 256;
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::ptr;
+use std::time::Instant;
+
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+/// How many instructions run between checks of the [Limits].
+const CHECK_INTERVAL: u64 = 1024;
 
-#[derive(Debug, Default)]
-pub struct Vm {
-    chunk: Option<Rc<RefCell<Chunk>>>,
+/// An ongoing function call. `slots` is the stack index of the frame's slot zero.
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame {
+    closure: ObjRef,
     ip: usize,
-    stack: Vec<Value>,
+    slots: usize,
+}
+
+impl CallFrame {
+    fn function(&self) -> &ObjFunction {
+        self.closure.as_closure().function.as_function()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.function().chunk
+    }
+}
+
+#[derive(Debug)]
+pub struct Vm {
+    frames: Vec<CallFrame>,
+    /// The innermost frame's chunk and instruction pointer, cached out of
+    /// `frames` so that decoding a byte is a pointer bump. See [Vm::load_frame].
+    chunk: *const Chunk,
+    ip: *const u8,
+    /// Fixed at STACK_MAX slots; only `stack[..stack_top]` is live.
+    stack: Box<[Value]>,
     stack_top: usize,
+    globals: Table,
+    init_string: Option<ObjRef>,
+    /// Upvalues still pointing into the stack, sorted by slot.
+    open_upvalues: Vec<ObjRef>,
+    heap: Heap,
+    /// Where each executed instruction is logged, if anywhere. See [Vm::set_trace].
+    trace: Option<Sink>,
+    debugger: Option<Box<Debugger>>,
+    profiler: Option<Box<Profiler>>,
+    /// Whether the run loop has to stop for the trace, the debugger or the
+    /// profiler before each instruction.
+    observed: bool,
+    /// Where `print` writes. See [Vm::set_output].
+    output: Sink,
+    /// How source is compiled for this VM.
+    compile_options: CompileOptions,
+    /// Details of the last compile or runtime error, until they are
+    /// reported or handed to the host.
+    error: Option<LoxError>,
+    limits: Limits,
+    /// Instructions left before [Vm::check_limits] runs, counted down from
+    /// `countdown_start`. Allocations and calls set it to zero to force a check.
+    countdown: u64,
+    countdown_start: u64,
+    /// Instructions executed in this run before the current countdown.
+    instructions: u64,
+    /// The frame count and stack height [Vm::run] returns to. Zero except
+    /// while [evaluating](Vm::evaluate) in a paused frame, where errors
+    /// unwind only this far.
+    base_frames: usize,
+    base_stack: usize,
+}
+
+struct Sink(Box<dyn Write>);
+
+impl Debug for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sink")
+    }
+}
+
+/// Writes the live stack slots, then the instruction at `offset`.
+fn write_trace(
+    out: &mut dyn Write,
+    stack: &[Value],
+    chunk: &Chunk,
+    offset: usize,
+) -> io::Result<()> {
+    write!(out, "          ")?;
+    for value in stack.iter() {
+        write!(out, "[ {} ]", value)?;
+    }
+    writeln!(out)?;
+    chunk.disassemble_instruction(offset, out)?;
+    Ok(())
+}
+
+/// Everything the running VM keeps alive.
+struct VmRoots<'a> {
+    stack: &'a [Value],
+    frames: &'a [CallFrame],
+    globals: &'a Table,
+    init_string: Option<ObjRef>,
+    open_upvalues: &'a [ObjRef],
+}
+
+impl GcRoots for VmRoots<'_> {
+    fn mark_roots(&self, heap: &mut Heap) {
+        for value in self.stack.iter() {
+            heap.mark_value(*value);
+        }
+        for frame in self.frames.iter() {
+            heap.mark_object(frame.closure);
+        }
+        for upvalue in self.open_upvalues.iter() {
+            heap.mark_object(*upvalue);
+        }
+        heap.mark_table(self.globals);
+        if let Some(init_string) = self.init_string {
+            heap.mark_object(init_string);
+        }
+    }
 }
 
 macro_rules! binary_op {
-    ($self:ident, $op:tt) => {
-        let b = $self.pop();
-        let a = $self.pop();
-        let oped = Value::map2((&a, &b), |(a, b)| a $op b );
-        $self.push(&oped);
+    ($self:ident, $value_type:ident, $op:tt) => {
+        if !$self.peek(0).is_number() || !$self.peek(1).is_number() {
+            return $self.runtime_error("Operands must be numbers.");
+        }
+        let b = $self.pop().as_number();
+        let a = $self.pop().as_number();
+        $self.push(&Value::$value_type(a $op b))?;
     };
 }
 
 impl Vm {
+    /// Creates a VM with the built-in natives defined. It prints to stdout
+    /// until given another [output](Vm::set_output).
     pub fn init_vm() -> Self {
-        Vm {
-            chunk: None,
-            ip: 0,
-            stack: Vec::with_capacity(STACK_MAX),
+        let mut vm = Vm {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            chunk: ptr::null(),
+            ip: ptr::null(),
+            stack: vec![Value::nil(); STACK_MAX].into_boxed_slice(),
             stack_top: 0,
-        }
+            globals: Table::new(),
+            init_string: None,
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
+            trace: None,
+            debugger: None,
+            profiler: None,
+            observed: false,
+            output: Sink(Box::new(io::stdout())),
+            compile_options: CompileOptions::default(),
+            error: None,
+            limits: Limits::default(),
+            countdown: 0,
+            countdown_start: 0,
+            instructions: 0,
+            base_frames: 0,
+            base_stack: 0,
+        };
+        #[cfg(feature = "trace_execution")]
+        vm.set_trace(Some(Box::new(io::stdout())));
+
+        vm.init_string = Some(vm.copy_string("init"));
+        vm.define_native("clock", 0, NativeFunction::Builtin(natives::clock))
+            .expect("a fresh VM has room on its stack");
+        vm
     }
 
     pub fn interpret(source: String) -> InterpretResult {
-        let mut chunk = Chunk::new();
+        Vm::init_vm().run_source(source)
+    }
+
+    /// Compiles and runs `source` on this VM, printing any error to stderr.
+    pub fn run_source(&mut self, source: String) -> InterpretResult {
+        let result = self
+            .compile(source)
+            .and_then(|function| self.run_script(function));
+        self.report(result)
+    }
+
+    /// Compiles `source` into the .loxc format without running it.
+    pub fn compile_bytecode(source: String, optimize: bool) -> Result<Vec<u8>, InterpretErrorCode> {
+        let mut vm = Vm::init_vm();
+        vm.set_optimize(optimize);
+        let result = vm.compile(source);
+        let function = vm.report(result)?;
+        Ok(serialize::serialize(function.as_function()))
+    }
+
+    /// Runs a script saved by [Vm::compile_bytecode].
+    /// A file that fails to load is reported like a compile error.
+    pub fn interpret_bytecode(bytes: &[u8]) -> InterpretResult {
+        Vm::init_vm().run_bytecode(bytes)
+    }
+
+    /// Loads and runs a .loxc file on this VM.
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> InterpretResult {
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        match serialize::deserialize(bytes, &mut self.heap, &roots) {
+            Ok(function) => {
+                let result = self.run_script(function);
+                self.report(result)
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                Vm::error(InterpretErrorCode::CompileError)
+            }
+        }
+    }
+
+    /// Compiles `source` and returns the disassembly of every function in it.
+    pub fn disassemble(source: String, optimize: bool) -> Result<String, InterpretErrorCode> {
         let mut vm = Vm::init_vm();
+        vm.set_optimize(optimize);
+        let result = vm.compile(source);
+        let function = vm.report(result)?;
+        Ok(Vm::disassembly(function))
+    }
+
+    /// Like [Vm::disassemble], for a file saved by [Vm::compile_bytecode].
+    pub fn disassemble_bytecode(bytes: &[u8]) -> Result<String, InterpretErrorCode> {
+        let mut vm = Vm::init_vm();
+
+        let roots = VmRoots {
+            stack: &vm.stack[..vm.stack_top],
+            frames: &vm.frames,
+            globals: &vm.globals,
+            init_string: vm.init_string,
+            open_upvalues: &vm.open_upvalues,
+        };
+        match serialize::deserialize(bytes, &mut vm.heap, &roots) {
+            Ok(function) => Ok(Vm::disassembly(function)),
+            Err(e) => {
+                eprintln!("{}", e);
+                Err(InterpretErrorCode::CompileError)
+            }
+        }
+    }
+
+    fn disassembly(function: ObjRef) -> String {
+        let mut out = Vec::new();
+        disassemble_function(function.as_function(), &mut out)
+            .expect("writing to a Vec can't fail");
+        String::from_utf8(out).expect("disassembly is UTF-8")
+    }
+
+    /// Turns the [optimizer](crate::optimizer) on or off for later compiles.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.compile_options.optimize = optimize;
+    }
+
+    /// Runs one REPL entry. Globals from earlier entries are still defined,
+    /// and the value of a final expression without a `;` is printed.
+    pub fn run_repl_entry(&mut self, source: String) -> InterpretResult {
+        let options = self.compile_options;
+        self.compile_options.print_trailing_expression = true;
+        let function = self.compile(source);
+        self.compile_options = options;
+        let result = function.and_then(|function| self.run_script(function));
+        self.report(result)
+    }
+
+    /// Prints the details of a failed `result` to stderr, the way the
+    /// command line reports errors.
+    fn report<T>(
+        &mut self,
+        result: Result<T, InterpretErrorCode>,
+    ) -> Result<T, InterpretErrorCode> {
+        if result.is_err() {
+            if let Some(error) = self.error.take() {
+                eprintln!("{}", error);
+            }
+        }
+        result
+    }
+
+    fn compile(&mut self, source: String) -> Result<ObjRef, InterpretErrorCode> {
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        compiler::compile(source, &mut self.heap, &roots, self.compile_options).map_err(|errors| {
+            self.error = Some(LoxError::Compile(errors));
+            InterpretErrorCode::CompileError
+        })
+    }
+
+    /// Logs the stack and each instruction to `out` before executing it,
+    /// or stops logging when `out` is `None`.
+    pub fn set_trace(&mut self, out: Option<Box<dyn Write>>) {
+        self.trace = out.map(Sink);
+        self.update_observed();
+    }
+
+    /// Hands control to `debugger` before each instruction of later runs.
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        self.debugger = debugger.map(Box::new);
+        self.update_observed();
+    }
+
+    /// Counts every instruction of later runs in `profiler`.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler.map(Box::new);
+        self.update_observed();
+    }
+
+    /// Stops profiling and returns what was counted.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        let profiler = self.profiler.take();
+        self.update_observed();
+        profiler.map(|profiler| *profiler)
+    }
+
+    fn update_observed(&mut self) {
+        self.observed = self.trace.is_some() || self.debugger.is_some() || self.profiler.is_some();
+    }
+
+    /// Sends the output of `print` statements to `out` instead of stdout.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.output = Sink(out);
+    }
+
+    /// Compiles and runs `source` on this VM. Unlike [Vm::run_source],
+    /// nothing is printed on failure; the error is returned instead.
+    pub fn execute(&mut self, source: &str) -> Result<(), LoxError> {
+        let result = self
+            .compile(source.to_string())
+            .and_then(|function| self.run_script(function));
+        result.map_err(|_| self.take_error())
+    }
+
+    /// Calls the global function, class or native `name` with `args` and
+    /// returns what it returns.
+    pub fn call_function(&mut self, name: &str, args: &[LoxValue]) -> Result<LoxValue, LoxError> {
+        let callee = match self.global(name) {
+            Some(callee) => callee,
+            None => {
+                let message = format!("Undefined variable '{}'.", name);
+                return Err(LoxError::runtime(&message));
+            }
+        };
+        if args.len() > u8::MAX as usize {
+            return Err(LoxError::runtime("Can't have more than 255 arguments."));
+        }
+
+        self.start_run();
+        self.push(&callee)
+            .expect("the stack is empty between host calls");
+        for arg in args {
+            // Each argument is on the stack before the next one allocates.
+            match self.import_value(arg) {
+                Ok(value) => self.push(&value).expect("255 arguments fit on the stack"),
+                Err(message) => {
+                    self.reset_stack();
+                    return Err(LoxError::runtime(&message));
+                }
+            }
+        }
+
+        let mut result = self.call_value(callee, args.len());
+        if result.is_ok() && !self.frames.is_empty() {
+            result = self.run();
+        }
+        match result {
+            Ok(()) => Ok(LoxValue::from(self.pop())),
+            Err(_) => Err(self.take_error()),
+        }
+    }
+
+    /// Defines a global `name` that runs `function` when called from Lox.
+    /// It takes one argument per entry in `params`; calls with the wrong
+    /// number or types of arguments are runtime errors before it runs.
+    pub fn define_function<F>(&mut self, name: &str, params: &[ArgType], function: F)
+    where
+        F: FnMut(&[LoxValue]) -> Result<LoxValue, String> + 'static,
+    {
+        let function = NativeFunction::Host {
+            params: params.to_vec(),
+            function: RefCell::new(Box::new(function)),
+        };
+        self.define_native(name, params.len(), function)
+            .expect("the stack is empty between host calls");
+    }
+
+    /// Returns the value of the global `name`, if it is defined.
+    pub fn get_global(&mut self, name: &str) -> Option<LoxValue> {
+        self.global(name).map(LoxValue::from)
+    }
+
+    /// Sets the global `name`, defining it if needed. Fails for
+    /// [LoxValue::Object], which can't be passed back in.
+    pub fn set_global(&mut self, name: &str, value: impl Into<LoxValue>) -> Result<(), LoxError> {
+        let name = self.copy_string(name);
+        self.push(&Value::obj(name))
+            .expect("the stack is empty between host calls");
+        let value = self.import_value(&value.into());
+        self.pop();
+        let value = value.map_err(|message| LoxError::runtime(&message))?;
+        self.globals.set(name, value);
+        Ok(())
+    }
+
+    /// A global the VM has never interned a name for can't be defined, so
+    /// the lookup doesn't allocate one.
+    fn global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(name)
+    }
+
+    /// Copies a host value into the VM, allocating strings on its heap.
+    fn import_value(&mut self, value: &LoxValue) -> Result<Value, String> {
+        match value {
+            LoxValue::Nil => Ok(Value::nil()),
+            LoxValue::Bool(b) => Ok(Value::boolean(*b)),
+            LoxValue::Number(n) => Ok(Value::number(*n)),
+            LoxValue::String(s) => Ok(Value::obj(self.copy_string(s))),
+            LoxValue::Object(s) => Err(format!("Can't pass the object {} into Lox.", s)),
+        }
+    }
+
+    fn take_error(&mut self) -> LoxError {
+        self.error.take().expect("a failed run records its error")
+    }
+
+    /// Bounds every later run, and a [resumed](Vm::resume) one.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.request_check();
+    }
+
+    /// Whether a run stopped at a limit and can be [resumed](Vm::resume).
+    /// Starting another run discards it.
+    pub fn is_suspended(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Continues a run stopped by a limit, usually after raising it, and
+    /// returns what the interrupted call returns: nil for a script.
+    pub fn resume(&mut self) -> Result<LoxValue, LoxError> {
+        if !self.is_suspended() {
+            return Err(LoxError::runtime("There is no run to resume."));
+        }
+        self.load_frame();
+        match self.run() {
+            Ok(()) => Ok(LoxValue::from(self.pop())),
+            Err(_) => Err(self.take_error()),
+        }
+    }
+
+    /// Instructions executed since the current or last run started.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions + (self.countdown_start - self.countdown)
+    }
+
+    /// Where a suspended or paused run is, innermost frame first.
+    pub fn stack_trace(&self) -> Vec<TraceLine> {
+        self.trace_lines(true)
+    }
+
+    /// With `paused`, the innermost frame is about to run the instruction at
+    /// its `ip`; otherwise it failed in the one before. Outer frames are
+    /// always in the call before their `ip`. Frames below the base belong to
+    /// a paused run, not to the expression being evaluated on top of it.
+    fn trace_lines(&self, paused: bool) -> Vec<TraceLine> {
+        (0..self.frames.len() - self.base_frames)
+            .map(|depth| {
+                let (frame, offset) = self.frame_at(depth, paused);
+                let function = frame.function();
+                TraceLine {
+                    line: function.chunk.get_line(offset),
+                    column: function.chunk.get_column(offset),
+                    function: function.name.map(|name| name.to_string()),
+                }
+            })
+            .collect()
+    }
+
+    /// The frame `depth` levels out from the innermost one, and the offset
+    /// of the instruction it is in.
+    fn frame_at(&self, depth: usize, paused: bool) -> (&CallFrame, usize) {
+        let frame = &self.frames[self.frames.len() - 1 - depth];
+        if paused && depth == 0 {
+            (frame, frame.ip)
+        } else {
+            (frame, frame.ip - 1)
+        }
+    }
+
+    /// How many frames a paused run has, and the line it is about to run.
+    pub(crate) fn position(&self) -> (usize, i32) {
+        let (frame, offset) = self.frame_at(0, true);
+        (self.frames.len(), frame.chunk().get_line(offset))
+    }
+
+    /// The outermost function of a paused run.
+    pub(crate) fn script(&self) -> Option<&ObjFunction> {
+        self.frames.first().map(|frame| frame.function())
+    }
+
+    /// The function of the frame `index` levels in from the outermost one.
+    pub(crate) fn frame_function(&self, index: usize) -> &ObjFunction {
+        self.frames[index].function()
+    }
+
+    /// The locals in scope in the frame `depth` levels out from the innermost
+    /// one of a paused run, by name, with the stack slot of each.
+    fn local_slots(&self, depth: usize) -> Vec<(String, usize)> {
+        let mut locals: Vec<(String, usize)> = Vec::new();
+        if depth >= self.frames.len() {
+            return locals;
+        }
+        let (frame, offset) = self.frame_at(depth, true);
+        for local in frame.function().locals.iter() {
+            let slot = frame.slots + local.slot;
+            if local.start <= offset && offset < local.end && slot < self.stack_top {
+                // An inner scope's variable hides an outer one of the same name.
+                locals.retain(|(name, _)| *name != local.name);
+                locals.push((local.name.clone(), slot));
+            }
+        }
+        locals.sort_by_key(|&(_, slot)| slot);
+        locals
+    }
+
+    /// The locals in scope in the frame `depth` levels out from the innermost
+    /// one of a paused run, by name.
+    pub fn locals(&self, depth: usize) -> Vec<(String, LoxValue)> {
+        self.local_slots(depth)
+            .into_iter()
+            .map(|(name, slot)| (name, LoxValue::from(self.stack[slot])))
+            .collect()
+    }
+
+    /// Every live stack slot of a paused run, bottom first.
+    pub fn stack_values(&self) -> Vec<LoxValue> {
+        self.stack[..self.stack_top]
+            .iter()
+            .map(|value| LoxValue::from(*value))
+            .collect()
+    }
+
+    /// Evaluates `expression` in the frame `depth` levels out from the
+    /// innermost one of a paused run, with its locals and `this` in scope.
+    /// Assigning to a local changes only the expression's copy of it.
+    pub fn evaluate(&mut self, depth: usize, expression: &str) -> Result<LoxValue, LoxError> {
+        let mut this = None;
+        let mut names = Vec::new();
+        let mut slots = Vec::new();
+        for (name, slot) in self.local_slots(depth) {
+            if name == "this" {
+                this = Some(slot);
+            } else {
+                names.push(name);
+                slots.push(slot);
+            }
+        }
+
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        let function = compiler::compile_expression(
+            expression,
+            &names,
+            this.is_some(),
+            &mut self.heap,
+            &roots,
+        )
+        .map_err(LoxError::Compile)?;
+
+        let base = (self.base_frames, self.base_stack);
+        self.base_frames = self.frames.len();
+        self.base_stack = self.stack_top;
+        let result = self.call_expression(function, this, &slots);
+        let result = match result {
+            Ok(()) => Ok(LoxValue::from(self.pop())),
+            Err(_) => {
+                // A limit leaves the frames in place; drop them anyway.
+                self.reset_stack();
+                Err(self.take_error())
+            }
+        };
+        (self.base_frames, self.base_stack) = base;
+        if !self.frames.is_empty() {
+            self.load_frame();
+        }
+        result
+    }
+
+    /// Runs a function from [compiler::compile_expression] with `this` and
+    /// the values in `args` slots.
+    fn call_expression(
+        &mut self,
+        function: ObjRef,
+        this: Option<usize>,
+        args: &[usize],
+    ) -> InterpretResult {
+        self.push(&Value::obj(function))?;
+        let closure = self.alloc(ObjKind::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.pop();
+        // Nothing allocates until the frame holds the closure.
+        let receiver = match this {
+            Some(slot) => self.stack[slot],
+            None => Value::obj(closure),
+        };
+        self.push(&receiver)?;
+        for &slot in args {
+            let arg = self.stack[slot];
+            self.push(&arg)?;
+        }
+        self.call(closure, args.len())?;
+        self.run()
+    }
+
+    /// Drops any suspended run and restarts the instruction count.
+    fn start_run(&mut self) {
+        self.reset_stack();
+        self.instructions = 0;
+        self.countdown = 0;
+        self.countdown_start = 0;
+    }
+
+    /// Makes the run loop check the limits before the next instruction.
+    fn request_check(&mut self) {
+        self.instructions = self.instructions_executed();
+        self.countdown = 0;
+        self.countdown_start = 0;
+    }
+
+    /// Stops the run if it is over any of the [Limits], leaving it ready to
+    /// resume; otherwise starts the next countdown.
+    #[cold]
+    fn check_limits(&mut self) -> InterpretResult {
+        self.request_check();
+        let limits = self.limits;
+        if let Some(max) = limits.max_instructions {
+            if self.instructions >= max {
+                return self.limit_exceeded(Limit::Instructions);
+            }
+        }
+        if let Some(max) = limits.max_call_depth {
+            if self.frames.len() > max {
+                return self.limit_exceeded(Limit::CallDepth);
+            }
+        }
+        if let Some(max) = limits.max_heap_bytes {
+            if self.heap.bytes_allocated() > max {
+                self.collect_garbage();
+                if self.heap.bytes_allocated() > max {
+                    return self.limit_exceeded(Limit::HeapBytes);
+                }
+            }
+        }
+        if let Some(deadline) = limits.deadline {
+            if Instant::now() >= deadline {
+                return self.limit_exceeded(Limit::Deadline);
+            }
+        }
 
-        compiler::compile(source, &mut chunk)?;
+        let mut next = CHECK_INTERVAL;
+        if let Some(max) = limits.max_instructions {
+            next = next.min(max - self.instructions);
+        }
+        self.countdown = next;
+        self.countdown_start = next;
+        Ok(())
+    }
 
-        vm.chunk = Some(Rc::new(RefCell::new(chunk)));
-        vm.ip = 0;
-        vm.run()?;
+    fn limit_exceeded(&mut self, limit: Limit) -> InterpretResult {
+        self.save_frame();
+        self.error = Some(LoxError::LimitExceeded(limit));
+        Vm::error(InterpretErrorCode::LimitExceeded)
+    }
+
+    fn run_script(&mut self, function: ObjRef) -> InterpretResult {
+        self.start_run();
+        self.push(&Value::obj(function))?;
+        let closure = self.alloc(ObjKind::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.pop();
+        self.push(&Value::obj(closure))?;
+        self.call(closure, 0)?;
+        self.run()?;
+        // The script's own return value.
+        self.pop();
 
         Ok(())
     }
 
+    fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: NativeFunction,
+    ) -> InterpretResult {
+        let name_string = self.copy_string(name);
+        self.push(&Value::obj(name_string))?;
+        let native = self.alloc(ObjKind::Native(ObjNative {
+            name: name.to_string(),
+            arity,
+            function,
+        }));
+        self.push(&Value::obj(native))?;
+        self.globals.set(name_string, Value::obj(native));
+        self.pop();
+        self.pop();
+        Ok(())
+    }
+
+    fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        let obj = self.heap.alloc(kind, &roots);
+        self.check_heap();
+        obj
+    }
+
+    fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        let obj = self.heap.copy_string(chars, &roots);
+        self.check_heap();
+        obj
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        let obj = self.heap.take_string(chars, &roots);
+        self.check_heap();
+        obj
+    }
+
+    fn collect_garbage(&mut self) {
+        let roots = VmRoots {
+            stack: &self.stack[..self.stack_top],
+            frames: &self.frames,
+            globals: &self.globals,
+            init_string: self.init_string,
+            open_upvalues: &self.open_upvalues,
+        };
+        self.heap.collect_garbage(&roots, None);
+    }
+
+    /// Checks the heap limit at the next instruction once it might be hit.
+    fn check_heap(&mut self) {
+        if let Some(max) = self.limits.max_heap_bytes {
+            if self.heap.bytes_allocated() > max {
+                self.request_check();
+            }
+        }
+    }
+
     pub fn error(reason: InterpretErrorCode) -> InterpretResult {
         Err(reason)
     }
 
+    /// Runs until the outermost frame returns or an error stops the run.
     fn run(&mut self) -> InterpretResult {
+        let result = self.run_loop();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.stop();
+        }
+        result
+    }
+
+    fn run_loop(&mut self) -> InterpretResult {
         loop {
-            #[cfg(feature = "trace_execution")]
-            unsafe {
-                print!("          ");
-                print!("{:?}", &VM.stack);
-                println!();
-                self.chunk
-                    .as_ref()
-                    .and_then(|chunk| Some(chunk.borrow().disassemble_instruction(self.ip)));
+            if self.countdown == 0 {
+                self.check_limits()?;
+            }
+            self.countdown -= 1;
+            if self.observed {
+                self.observe_instruction()?;
             }
 
             let instruction: OpCode = self.read_byte::<OpCode>();
             match instruction {
                 OP_CONSTANT => {
                     let constant = self.read_constant();
-                    self.push(&constant);
+                    self.push(&constant)?;
+                }
+                OP_CONSTANT_LONG => {
+                    let constant = self.read_constant_long();
+                    self.push(&constant)?;
+                }
+                OP_NIL => self.push(&Value::nil())?,
+                OP_TRUE => self.push(&Value::boolean(true))?,
+                OP_FALSE => self.push(&Value::boolean(false))?,
+                OP_POP => {
+                    self.pop();
+                }
+                OP_GET_LOCAL => {
+                    let slot = self.read_byte::<usize>();
+                    let value = self.stack[self.frame().slots + slot];
+                    self.push(&value)?;
+                }
+                OP_SET_LOCAL => {
+                    let slot = self.read_byte::<usize>();
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek(0);
+                }
+                OP_GET_GLOBAL | OP_GET_GLOBAL_LONG => {
+                    let name = self.read_name(instruction);
+                    let value = match self.globals.get(name) {
+                        Some(value) => value,
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name);
+                            return self.runtime_error(&message);
+                        }
+                    };
+                    self.push(&value)?;
+                }
+                OP_DEFINE_GLOBAL | OP_DEFINE_GLOBAL_LONG => {
+                    let name = self.read_name(instruction);
+                    let value = self.peek(0);
+                    self.globals.set(name, value);
+                    self.pop();
+                }
+                OP_SET_GLOBAL | OP_SET_GLOBAL_LONG => {
+                    let name = self.read_name(instruction);
+                    let value = self.peek(0);
+                    if self.globals.set(name, value) {
+                        self.globals.delete(name);
+                        let message = format!("Undefined variable '{}'.", name);
+                        return self.runtime_error(&message);
+                    }
+                }
+                OP_GET_UPVALUE => {
+                    let slot = self.read_byte::<usize>();
+                    let upvalue = self.frame().closure.as_closure().upvalues[slot];
+                    let value = match upvalue.as_upvalue().state.get() {
+                        UpvalueState::Open(location) => self.stack[location],
+                        UpvalueState::Closed(value) => value,
+                    };
+                    self.push(&value)?;
+                }
+                OP_SET_UPVALUE => {
+                    let slot = self.read_byte::<usize>();
+                    let upvalue = self.frame().closure.as_closure().upvalues[slot];
+                    let value = self.peek(0);
+                    let state = &upvalue.as_upvalue().state;
+                    match state.get() {
+                        UpvalueState::Open(location) => self.stack[location] = value,
+                        UpvalueState::Closed(_) => state.set(UpvalueState::Closed(value)),
+                    }
+                }
+                OP_GET_PROPERTY | OP_GET_PROPERTY_LONG => {
+                    if !self.peek(0).is_instance() {
+                        return self.runtime_error("Only instances have properties.");
+                    }
+
+                    let instance = self.peek(0).as_obj();
+                    let name = self.read_name(instruction);
+                    let field = instance.as_instance().fields.borrow().get(name);
+                    if let Some(value) = field {
+                        self.pop(); // Instance.
+                        self.push(&value)?;
+                    } else {
+                        self.bind_method(instance.as_instance().class, name)?;
+                    }
+                }
+                OP_SET_PROPERTY | OP_SET_PROPERTY_LONG => {
+                    if !self.peek(1).is_instance() {
+                        return self.runtime_error("Only instances have fields.");
+                    }
+
+                    let instance = self.peek(1).as_obj();
+                    let name = self.read_name(instruction);
+                    instance
+                        .as_instance()
+                        .fields
+                        .borrow_mut()
+                        .set(name, self.peek(0));
+                    let value = self.pop();
+                    self.pop(); // Instance.
+                    self.push(&value)?;
+                }
+                OP_GET_SUPER | OP_GET_SUPER_LONG => {
+                    let name = self.read_name(instruction);
+                    if !self.peek(0).is_class() {
+                        return self.runtime_error("Superclass must be a class.");
+                    }
+                    let superclass = self.pop().as_obj();
+                    self.bind_method(superclass, name)?;
+                }
+                OP_EQUAL => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(&Value::boolean(a == b))?;
+                }
+                OP_GREATER => {
+                    binary_op!(self, boolean, >);
+                }
+                OP_LESS => {
+                    binary_op!(self, boolean, <);
                 }
                 OP_ADD => {
-                    binary_op!(self, +);
+                    if self.peek(0).is_string() && self.peek(1).is_string() {
+                        self.concatenate()?;
+                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
+                        let b = self.pop().as_number();
+                        let a = self.pop().as_number();
+                        self.push(&Value::number(a + b))?;
+                    } else {
+                        return self.runtime_error("Operands must be two numbers or two strings.");
+                    }
                 }
                 OP_SUBTRACT => {
-                    binary_op!(self, -);
+                    binary_op!(self, number, -);
                 }
                 OP_MULTIPLY => {
-                    binary_op!(self, *);
+                    binary_op!(self, number, *);
                 }
                 OP_DIVIDE => {
-                    binary_op!(self, /);
+                    binary_op!(self, number, /);
+                }
+                OP_NOT => {
+                    let value = self.pop();
+                    self.push(&Value::boolean(value.is_falsey()))?;
                 }
                 OP_NEGATE => {
-                    let neg = self.pop().map(|f| -f);
-                    self.push(&neg);
+                    if !self.peek(0).is_number() {
+                        return self.runtime_error("Operand must be a number.");
+                    }
+                    let neg = -self.pop().as_number();
+                    self.push(&Value::number(neg))?;
+                }
+                OP_PRINT => {
+                    let value = self.pop();
+                    if let Err(e) = writeln!(self.output.0, "{}", value) {
+                        return self.runtime_error(&format!("Could not write output: {}.", e));
+                    }
+                }
+                OP_JUMP => {
+                    let offset = self.read_short();
+                    self.ip = unsafe { self.ip.add(offset as usize) };
+                }
+                OP_JUMP_IF_FALSE => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.ip = unsafe { self.ip.add(offset as usize) };
+                    }
+                }
+                OP_LOOP => {
+                    let offset = self.read_short();
+                    self.ip = unsafe { self.ip.sub(offset as usize) };
+                }
+                OP_CALL => {
+                    let arg_count = self.read_byte::<usize>();
+                    let callee = self.peek(arg_count);
+                    self.call_value(callee, arg_count)?;
+                }
+                OP_INVOKE | OP_INVOKE_LONG => {
+                    let method = self.read_name(instruction);
+                    let arg_count = self.read_byte::<usize>();
+                    self.invoke(method, arg_count)?;
+                }
+                OP_SUPER_INVOKE | OP_SUPER_INVOKE_LONG => {
+                    let method = self.read_name(instruction);
+                    let arg_count = self.read_byte::<usize>();
+                    if !self.peek(0).is_class() {
+                        return self.runtime_error("Superclass must be a class.");
+                    }
+                    let superclass = self.pop().as_obj();
+                    self.invoke_from_class(superclass, method, arg_count)?;
+                }
+                OP_CLOSURE | OP_CLOSURE_LONG => {
+                    let function = self.read_name(instruction);
+                    let upvalue_count = function.as_function().upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte::<u8>();
+                        let index = self.read_byte::<usize>();
+                        if is_local == 1 {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.as_closure().upvalues[index]);
+                        }
+                    }
+                    let closure = self.alloc(ObjKind::Closure(ObjClosure { function, upvalues }));
+                    self.push(&Value::obj(closure))?;
+                }
+                OP_CLOSE_UPVALUE => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
                 }
                 OP_RETURN => {
-                    println!("{:?}", self.pop());
-                    return Ok(());
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack_top = frame.slots;
+                    self.push(&result)?;
+                    if self.frames.len() == self.base_frames {
+                        // Leave the result for whoever made the outermost call.
+                        return Ok(());
+                    }
+
+                    self.load_frame();
+                }
+                OP_CLASS | OP_CLASS_LONG => {
+                    let name = self.read_name(instruction);
+                    let class = self.alloc(ObjKind::Class(ObjClass::new(name)));
+                    self.push(&Value::obj(class))?;
+                }
+                OP_INHERIT => {
+                    let superclass = self.peek(1);
+                    if !superclass.is_class() {
+                        return self.runtime_error("Superclass must be a class.");
+                    }
+                    if !self.peek(0).is_class() {
+                        return self.runtime_error("Only classes have methods.");
+                    }
+
+                    let subclass = self.peek(0).as_obj();
+                    let superclass = superclass.as_obj();
+                    let methods = superclass.as_class().methods.borrow();
+                    subclass.as_class().methods.borrow_mut().add_all(&methods);
+                    self.pop(); // Subclass.
+                }
+                OP_METHOD | OP_METHOD_LONG => {
+                    let name = self.read_name(instruction);
+                    self.define_method(name)?;
+                }
+                OP_GET_LOCAL_0 | OP_GET_LOCAL_1 | OP_GET_LOCAL_2 | OP_GET_LOCAL_3 => {
+                    let slot = instruction as usize - OP_GET_LOCAL_0 as usize;
+                    let value = self.stack[self.frame().slots + slot];
+                    self.push(&value)?;
+                }
+                OP_ADD_CONSTANT => {
+                    let constant = self.read_constant();
+                    if !self.peek(0).is_number() {
+                        return self.runtime_error("Operands must be two numbers or two strings.");
+                    }
+                    let top = self.stack_top - 1;
+                    self.stack[top] = Value::number(self.stack[top].as_number() + constant.as_number());
+                }
+                OP_LESS_JUMP_IF_FALSE => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        return self.runtime_error("Operands must be numbers.");
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    let less = a < b;
+                    self.push(&Value::boolean(less))?;
+                    let offset = self.read_short();
+                    if !less {
+                        self.ip = unsafe { self.ip.add(offset as usize) };
+                    }
+                }
+                OP_INCREMENT_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte::<usize>();
+                    if !self.stack[slot].is_number() {
+                        return self.runtime_error("Operands must be two numbers or two strings.");
+                    }
+                    let value = Value::number(self.stack[slot].as_number() + 1.0);
+                    self.stack[slot] = value;
+                    self.push(&value)?;
                 }
                 _ => {
                     break;
                 }
             };
         }
-        return Ok(());
+        Ok(())
+    }
+
+    fn concatenate(&mut self) -> InterpretResult {
+        // Keep both operands on the stack while allocating, so the GC can see them.
+        let b = self.peek(0).as_obj();
+        let a = self.peek(1).as_obj();
+        let mut chars = a.as_string().chars.clone();
+        chars.push_str(&b.as_string().chars);
+        let result = self.take_string(chars);
+        self.pop();
+        self.pop();
+        self.push(&Value::obj(result))
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> InterpretResult {
+        if callee.is_obj() {
+            let obj = callee.as_obj();
+            match &obj.kind {
+                ObjKind::BoundMethod(bound) => {
+                    let receiver = self.stack_top - arg_count - 1;
+                    self.stack[receiver] = bound.receiver;
+                    return self.call(bound.method, arg_count);
+                }
+                ObjKind::Class(class) => {
+                    let instance = self.alloc(ObjKind::Instance(ObjInstance::new(obj)));
+                    let receiver = self.stack_top - arg_count - 1;
+                    self.stack[receiver] = Value::obj(instance);
+
+                    let initializer = class.methods.borrow().get(self.init_string.unwrap());
+                    if let Some(initializer) = initializer {
+                        return self.call(initializer.as_obj(), arg_count);
+                    } else if arg_count != 0 {
+                        let message = format!("Expected 0 arguments but got {}.", arg_count);
+                        return self.runtime_error(&message);
+                    }
+                    return Ok(());
+                }
+                ObjKind::Closure(_) => return self.call(obj, arg_count),
+                ObjKind::Native(native) => {
+                    if arg_count != native.arity {
+                        let message = format!(
+                            "Expected {} arguments but got {}.",
+                            native.arity, arg_count
+                        );
+                        return self.runtime_error(&message);
+                    }
+                    let args_start = self.stack_top - arg_count;
+                    let result = match &native.function {
+                        NativeFunction::Builtin(function) => {
+                            function(&self.stack[args_start..self.stack_top])
+                        }
+                        NativeFunction::Host { params, function } => {
+                            return self.call_host(&native.name, params, function, args_start);
+                        }
+                    };
+                    self.stack_top = args_start - 1;
+                    self.push(&result)?;
+                    return Ok(());
+                }
+                _ => {} // Non-callable object type.
+            }
+        }
+        self.runtime_error("Can only call functions and classes.")
+    }
+
+    /// Checks the arguments from `args_start` up against `params`, then
+    /// replaces them and the callee with what `function` returns.
+    fn call_host(
+        &mut self,
+        name: &str,
+        params: &[ArgType],
+        function: &RefCell<HostFn>,
+        args_start: usize,
+    ) -> InterpretResult {
+        let mut args = Vec::with_capacity(params.len());
+        for (i, param) in params.iter().enumerate() {
+            let arg = self.stack[args_start + i];
+            if !param.accepts(arg) {
+                let message = format!("Argument {} of {}() must be {}.", i + 1, name, param);
+                return self.runtime_error(&message);
+            }
+            args.push(LoxValue::from(arg));
+        }
+
+        let result = (*function.borrow_mut())(&args);
+        let result = match result.and_then(|value| self.import_value(&value)) {
+            Ok(result) => result,
+            Err(message) => return self.runtime_error(&message),
+        };
+        self.stack_top = args_start - 1;
+        self.push(&result)
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> InterpretResult {
+        let arity = closure.as_closure().function.as_function().arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return self.runtime_error(&message);
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return self.runtime_error("Stack overflow.");
+        }
+
+        self.save_frame();
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack_top - arg_count - 1,
+        });
+        self.load_frame();
+        if let Some(max) = self.limits.max_call_depth {
+            if self.frames.len() > max {
+                self.request_check();
+            }
+        }
+        Ok(())
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, arg_count: usize) -> InterpretResult {
+        let method = class.as_class().methods.borrow().get(name);
+        match method {
+            Some(method) => self.call(method.as_obj(), arg_count),
+            None => {
+                let message = format!("Undefined property '{}'.", name);
+                self.runtime_error(&message)
+            }
+        }
+    }
+
+    /// Calls a method without materializing a bound method,
+    /// unless `name` turns out to be a field holding a callable.
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> InterpretResult {
+        let receiver = self.peek(arg_count);
+        if !receiver.is_instance() {
+            return self.runtime_error("Only instances have methods.");
+        }
+
+        let instance = receiver.as_obj();
+        let field = instance.as_instance().fields.borrow().get(name);
+        if let Some(value) = field {
+            let slot = self.stack_top - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(instance.as_instance().class, name, arg_count)
+    }
+
+    /// Replaces the instance on top of the stack with its method `name` bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> InterpretResult {
+        let method = class.as_class().methods.borrow().get(name);
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let message = format!("Undefined property '{}'.", name);
+                return self.runtime_error(&message);
+            }
+        };
+
+        let bound = self.alloc(ObjKind::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0),
+            method: method.as_obj(),
+        }));
+        self.pop();
+        self.push(&Value::obj(bound))?;
+        Ok(())
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> InterpretResult {
+        // Compiled code always satisfies these, but a .loxc file might not.
+        let method = self.peek(0);
+        if !method.is_obj() || !matches!(method.as_obj().kind, ObjKind::Closure(_)) {
+            return self.runtime_error("Only functions can be methods.");
+        }
+        if !self.peek(1).is_class() {
+            return self.runtime_error("Only classes have methods.");
+        }
+
+        let class = self.peek(1).as_obj();
+        class
+            .as_class()
+            .methods
+            .borrow_mut()
+            .set(name, method);
+        self.pop();
+        Ok(())
+    }
+
+    /// Reuses the open upvalue for `slot` if there is one, so closures share variables.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let found = self
+            .open_upvalues
+            .binary_search_by_key(&slot, |upvalue| match upvalue.as_upvalue().state.get() {
+                UpvalueState::Open(location) => location,
+                UpvalueState::Closed(_) => unreachable!("closed upvalue in the open list"),
+            });
+
+        match found {
+            Ok(i) => self.open_upvalues[i],
+            Err(i) => {
+                let created = self.alloc(ObjKind::Upvalue(ObjUpvalue::new(slot)));
+                self.open_upvalues.insert(i, created);
+                created
+            }
+        }
+    }
+
+    /// Closes every open upvalue pointing at `last` or above in the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let state = &upvalue.as_upvalue().state;
+            let location = match state.get() {
+                UpvalueState::Open(location) => location,
+                UpvalueState::Closed(_) => unreachable!("closed upvalue in the open list"),
+            };
+            if location < last {
+                break;
+            }
+            state.set(UpvalueState::Closed(self.stack[location]));
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Records the error with a stack trace, innermost frame first.
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        self.save_frame();
+        self.error = Some(LoxError::Runtime {
+            message: message.to_string(),
+            trace: self.trace_lines(false),
+        });
+
+        self.reset_stack();
+        Vm::error(InterpretErrorCode::RuntimeError)
+    }
+
+    /// Lets the debugger stop before the next instruction, then profiles
+    /// and traces it.
+    #[cold]
+    fn observe_instruction(&mut self) -> InterpretResult {
+        if let Some(mut debugger) = self.debugger.take() {
+            self.save_frame();
+            let go_on = debugger.before_instruction(self);
+            self.debugger = Some(debugger);
+            if !go_on {
+                self.error = Some(LoxError::Stopped);
+                self.reset_stack();
+                return Vm::error(InterpretErrorCode::Stopped);
+            }
+        }
+        if let Some(mut profiler) = self.profiler.take() {
+            self.save_frame();
+            profiler.before_instruction(self);
+            self.profiler = Some(profiler);
+        }
+        if self.trace.is_some() {
+            self.trace_instruction()?;
+        }
+        Ok(())
+    }
+
+    fn trace_instruction(&mut self) -> InterpretResult {
+        let offset = unsafe { self.ip.offset_from(self.chunk().code.as_ptr()) } as usize;
+        let chunk = unsafe { &*self.chunk };
+        let Sink(out) = self.trace.as_mut().unwrap();
+
+        if let Err(e) = write_trace(out, &self.stack[..self.stack_top], chunk, offset) {
+            self.set_trace(None);
+            return self.runtime_error(&format!("Could not write trace: {}.", e));
+        }
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    /// Points the cached chunk and ip at the innermost frame.
+    fn load_frame(&mut self) {
+        let frame = self.frame();
+        // The frame's closure keeps the function, and so its chunk, alive and in place.
+        let chunk: *const Chunk = frame.chunk();
+        self.ip = unsafe { (*chunk).code.as_ptr().add(frame.ip) };
+        self.chunk = chunk;
+    }
+
+    /// Writes the cached ip back into the innermost frame, before another frame
+    /// is pushed or anything needs to read `CallFrame::ip`.
+    fn save_frame(&mut self) {
+        if self.frames.is_empty() {
+            return;
+        }
+        let offset = unsafe { self.ip.offset_from(self.chunk().code.as_ptr()) };
+        self.frame_mut().ip = offset as usize;
+    }
+
+    fn chunk(&self) -> &Chunk {
+        unsafe { &*self.chunk }
     }
 
     fn read_byte<T>(&mut self) -> T
     where
         T: From<u8>,
     {
-        let ret = self.chunk.as_ref().unwrap().borrow().code[self.ip];
-        self.ip += 1;
-        ret.into()
+        // Every chunk ends in OP_RETURN, so ip never runs past the code.
+        let byte = unsafe { *self.ip };
+        self.ip = unsafe { self.ip.add(1) };
+        byte.into()
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte::<u8>() as u16;
+        let low = self.read_byte::<u8>() as u16;
+        (high << 8) | low
     }
 
     fn read_constant(&mut self) -> Value {
         let cursor: usize = self.read_byte::<usize>();
-        self.chunk.as_ref().unwrap().borrow().constants[cursor].clone()
+        self.chunk().constants[cursor]
+    }
+
+    /// Reads a 24-bit big-endian constant index, as emitted for the `_LONG` opcodes.
+    fn read_constant_long(&mut self) -> Value {
+        let high = self.read_byte::<usize>();
+        let middle = self.read_byte::<usize>();
+        let low = self.read_byte::<usize>();
+        self.chunk().constants[(high << 16) | (middle << 8) | low]
     }
 
-    #[allow(dead_code)]
+    /// Reads the object constant `instruction` names: a string, or the
+    /// function of an OP_CLOSURE. The `_LONG` forms index it with 24 bits.
+    fn read_name(&mut self, instruction: OpCode) -> ObjRef {
+        let constant = match instruction {
+            OP_GET_GLOBAL_LONG
+            | OP_DEFINE_GLOBAL_LONG
+            | OP_SET_GLOBAL_LONG
+            | OP_GET_PROPERTY_LONG
+            | OP_SET_PROPERTY_LONG
+            | OP_GET_SUPER_LONG
+            | OP_INVOKE_LONG
+            | OP_SUPER_INVOKE_LONG
+            | OP_CLOSURE_LONG
+            | OP_CLASS_LONG
+            | OP_METHOD_LONG => self.read_constant_long(),
+            _ => self.read_constant(),
+        };
+        constant.as_obj()
+    }
+
+    /// Drops the frames and values above the base, closing their upvalues.
     fn reset_stack(&mut self) {
-        self.stack.clear();
-        self.stack_top = 0;
+        self.close_upvalues(self.base_stack);
+        self.frames.truncate(self.base_frames);
+        self.stack_top = self.base_stack;
     }
 
-    fn push(&mut self, value: &Value) {
-        self.stack.insert(self.stack_top, value.clone());
+    fn push(&mut self, value: &Value) -> InterpretResult {
+        if self.stack_top == STACK_MAX {
+            return self.runtime_error("Stack overflow.");
+        }
+        self.stack[self.stack_top] = *value;
         self.stack_top += 1;
+        Ok(())
     }
 
     fn pop(&mut self) -> Value {
         self.stack_top -= 1;
-        self.stack.remove(self.stack_top)
+        self.stack[self.stack_top]
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack_top - 1 - distance]
+    }
+}
+
+mod natives {
+    use crate::value::Value;
+
+    pub fn clock(_args: &[Value]) -> Value {
+        use std::time::*;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        Value::number(millis as f64)
     }
 }
 
@@ -135,6 +1510,10 @@ pub enum InterpretErrorCode {
     Ok, // stub
     CompileError,
     RuntimeError,
+    /// A run went over one of the [Limits] and can be resumed.
+    LimitExceeded,
+    /// The user quit the debugger.
+    Stopped,
 }
 
 use std::error::Error;
@@ -0,0 +1,154 @@
+//! Drives `--debug` through stdin. The debugger's prompts and replies share
+//! stdout with the script's own output. The last test attaches a debugger
+//! from Rust instead.
+
+use rlox_iii::debugger::Debugger;
+use rlox_iii::host::{LoxError, LoxValue};
+use rlox_iii::vm::Vm;
+
+use std::io::{self, Write};
+use std::process::{Command, Output, Stdio};
+
+const SCRIPT: &str = "\
+fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var total = 0;
+for (var i = 0; i < 3; i = i + 1) {
+  total = add(total, i);
+}
+print total;
+";
+
+fn debug(name: &str, commands: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("rlox-debugger-{}.lox", name));
+    std::fs::write(&path, SCRIPT).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+        .arg("--debug")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(commands.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+/// The debugger's replies, one per line, with the script path shortened.
+fn replies(output: &Output, name: &str) -> Vec<String> {
+    let path = std::env::temp_dir().join(format!("rlox-debugger-{}.lox", name));
+    String::from_utf8_lossy(&output.stdout)
+        .replace(&path.display().to_string(), "test.lox")
+        .replace("(debug) ", "")
+        .lines()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[test]
+fn breakpoints_stop_in_calls() {
+    let out = debug("breakpoints", "break 2\ncontinue\nbacktrace\nlocals\ncontinue\nlocals\ndelete rlox-debugger-breakpoints.lox:2\nbreak nowhere.lox:2\ncontinue\n");
+    let name = "breakpoints";
+    assert_eq!(
+        replies(&out, name),
+        vec![
+            "Stopped at test.lox:4 in script",
+            "   4  }",
+            "Breakpoint at test.lox:2",
+            "Breakpoint at test.lox:2 in add()",
+            "   2    var sum = a + b;",
+            "*#0 test.lox:2 in add()",
+            " #1 test.lox:7 in script",
+            "a = 0",
+            "b = 0",
+            "Breakpoint at test.lox:2 in add()",
+            "   2    var sum = a + b;",
+            "a = 0",
+            "b = 1",
+            "Deleted breakpoint at test.lox:2",
+            "No source file named 'nowhere.lox'.",
+            "3",
+        ]
+    );
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn stepping_into_over_and_out() {
+    let out = debug(
+        "stepping",
+        "break 7\ncontinue\nstep\nnext\nlocals\nfinish\nnext\nnext\nlocals\n",
+    );
+    assert_eq!(
+        replies(&out, "stepping")[3..],
+        [
+            "Breakpoint at test.lox:7 in script",
+            "   7    total = add(total, i);",
+            "Stopped at test.lox:2 in add()",
+            "   2    var sum = a + b;",
+            "Stopped at test.lox:3 in add()",
+            "   3    return sum;",
+            "a = 0",
+            "b = 0",
+            "sum = 0",
+            "Stopped at test.lox:7 in script",
+            "   7    total = add(total, i);",
+            "Stopped at test.lox:8 in script",
+            "   8  }",
+            "Stopped at test.lox:6 in script",
+            "   6  for (var i = 0; i < 3; i = i + 1) {",
+            "i = 0",
+            "",
+            "3",
+        ]
+    );
+}
+
+#[test]
+fn print_evaluates_in_the_selected_frame() {
+    let commands = "break 3\ncontinue\nprint sum * 10\nprint add(sum, 100)\nprint i\nframe 1\nprint i + total\nprint i +\nquit\n";
+    let out = debug("print", commands);
+    assert_eq!(
+        replies(&out, "print")[5..],
+        [
+            "0",
+            "100",
+            "Undefined variable 'i'.",
+            "[line 1:1] in script",
+            "#1 test.lox:7 in script",
+            "   7    total = add(total, i);",
+            "0",
+            "[line 1:4] Error at end: Expect expression.",
+        ]
+    );
+    // Quitting stops the script before it prints its total.
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "Stopped by the user.\n"
+    );
+    assert_eq!(out.status.code(), Some(130));
+}
+
+#[test]
+fn quitting_hands_control_back_to_the_host() {
+    let mut vm = Vm::init_vm();
+    vm.set_output(Box::new(io::sink()));
+    let input = Box::new(io::Cursor::new("quit\n"));
+    let debugger = Debugger::new("test.lox", SCRIPT, input, Box::new(io::sink()));
+    vm.set_debugger(Some(debugger));
+    assert_eq!(vm.execute(SCRIPT), Err(LoxError::Stopped));
+    assert!(!vm.is_suspended());
+
+    vm.set_debugger(None);
+    vm.execute(SCRIPT).unwrap();
+    assert_eq!(vm.get_global("total"), Some(LoxValue::Number(3.0)));
+}
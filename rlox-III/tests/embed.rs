@@ -0,0 +1,209 @@
+//! Drives the VM from Rust the way an embedding program would: nothing here
+//! goes through stdout or stderr.
+
+use rlox_iii::host::{ArgType, Limit, Limits, LoxError, LoxValue, TraceLine};
+use rlox_iii::vm::Vm;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A `Write` whose bytes the test can still read after handing it to the VM.
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn calls_lox_functions_by_name() {
+    let mut vm = Vm::init_vm();
+    vm.execute("fun greet(name) { return \"hi \" + name; } fun twice(n) { return n * 2; }")
+        .unwrap();
+
+    assert_eq!(
+        vm.call_function("greet", &["lox".into()]),
+        Ok(LoxValue::String("hi lox".to_string()))
+    );
+    assert_eq!(
+        vm.call_function("twice", &[21.0.into()]),
+        Ok(LoxValue::Number(42.0))
+    );
+    assert_eq!(
+        vm.call_function("missing", &[]),
+        Err(LoxError::Runtime {
+            message: "Undefined variable 'missing'.".to_string(),
+            trace: vec![],
+        })
+    );
+}
+
+#[test]
+fn host_functions_get_checked_arguments() {
+    let mut vm = Vm::init_vm();
+    let calls = Rc::new(RefCell::new(0));
+    let counter = Rc::clone(&calls);
+    vm.define_function("repeat", &[ArgType::String, ArgType::Number], move |args| {
+        *counter.borrow_mut() += 1;
+        match args {
+            [LoxValue::String(s), LoxValue::Number(n)] if *n >= 0.0 => {
+                Ok(LoxValue::String(s.repeat(*n as usize)))
+            }
+            _ => Err("Count must not be negative.".to_string()),
+        }
+    });
+
+    vm.execute("var a = repeat(\"ab\", 3);").unwrap();
+    assert_eq!(
+        vm.get_global("a"),
+        Some(LoxValue::String("ababab".to_string()))
+    );
+
+    let error = vm.execute("fun f() { repeat(1, 2); }\nf();").unwrap_err();
+    assert_eq!(
+        error,
+        LoxError::Runtime {
+            message: "Argument 1 of repeat() must be a string.".to_string(),
+            trace: vec![
+                TraceLine {
+                    line: 1,
+                    column: 22,
+                    function: Some("f".to_string())
+                },
+                TraceLine {
+                    line: 2,
+                    column: 3,
+                    function: None
+                },
+            ],
+        }
+    );
+    assert!(matches!(
+        vm.execute("repeat(\"x\", -1);"),
+        Err(LoxError::Runtime { message, .. }) if message == "Count must not be negative."
+    ));
+    assert!(vm.execute("repeat(\"x\");").is_err());
+    assert_eq!(*calls.borrow(), 2);
+}
+
+#[test]
+fn globals_cross_between_rust_and_lox() {
+    let mut vm = Vm::init_vm();
+    let out = Captured::default();
+    vm.set_output(Box::new(out.clone()));
+
+    vm.set_global("limit", 3.0).unwrap();
+    vm.set_global("name", "lox").unwrap();
+    vm.execute("for (var i = 0; i < limit; i = i + 1) print name; var done = true;")
+        .unwrap();
+    assert_eq!(out.text(), "lox\nlox\nlox\n");
+    assert_eq!(vm.get_global("done"), Some(LoxValue::Bool(true)));
+    assert_eq!(vm.get_global("nothing"), None);
+
+    vm.execute("class Point {}").unwrap();
+    let point = vm.call_function("Point", &[]).unwrap();
+    assert_eq!(point, LoxValue::Object("#<Instance of Point>".to_string()));
+    assert!(vm.set_global("p", point).is_err());
+}
+
+#[test]
+fn any_nan_stays_a_number() {
+    let mut vm = Vm::init_vm();
+    // With nan_boxing, these bits would otherwise read as an object pointer.
+    let nan = f64::from_bits(0xfffc_0000_0000_1000);
+    vm.set_global("n", nan).unwrap();
+    vm.execute("var same = n == n; var sum = n + 1;").unwrap();
+    assert_eq!(vm.get_global("same"), Some(LoxValue::Bool(false)));
+    assert!(matches!(vm.get_global("sum"), Some(LoxValue::Number(x)) if x.is_nan()));
+}
+
+#[test]
+fn compile_errors_are_structured() {
+    let mut vm = Vm::init_vm();
+    match vm.execute("var a = ;\nprint a +;") {
+        Err(LoxError::Compile(errors)) => {
+            let lines: Vec<i64> = errors.iter().map(|e| e.line).collect();
+            assert_eq!(lines, vec![1, 2]);
+            let columns: Vec<i64> = errors.iter().map(|e| e.column).collect();
+            assert_eq!(columns, vec![9, 10]);
+            assert_eq!(
+                errors[0].to_string(),
+                "[line 1:9] Error at ';': Expect expression."
+            );
+        }
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+    // The VM is still usable afterwards.
+    vm.execute("var a = 1;").unwrap();
+    assert_eq!(vm.get_global("a"), Some(LoxValue::Number(1.0)));
+}
+
+#[test]
+fn instruction_limit_suspends_and_resumes() {
+    let mut vm = Vm::init_vm();
+    vm.execute("fun count(n) { var i = 0; while (i < n) i = i + 1; return i; }")
+        .unwrap();
+    vm.set_limits(Limits {
+        max_instructions: Some(500),
+        ..Limits::default()
+    });
+
+    assert_eq!(
+        vm.call_function("count", &[1000.0.into()]),
+        Err(LoxError::LimitExceeded(Limit::Instructions))
+    );
+    assert!(vm.is_suspended());
+    assert_eq!(vm.instructions_executed(), 500);
+    assert_eq!(vm.stack_trace()[0].function, Some("count".to_string()));
+
+    vm.set_limits(Limits::default());
+    assert_eq!(vm.resume(), Ok(LoxValue::Number(1000.0)));
+    assert!(!vm.is_suspended());
+    assert!(vm.resume().is_err());
+}
+
+#[test]
+fn call_depth_heap_and_deadline_limits() {
+    let mut vm = Vm::init_vm();
+    vm.set_limits(Limits {
+        max_call_depth: Some(10),
+        ..Limits::default()
+    });
+    let error = vm
+        .execute("fun f(n) { return f(n + 1); } f(0);")
+        .unwrap_err();
+    assert_eq!(error, LoxError::LimitExceeded(Limit::CallDepth));
+    assert_eq!(vm.stack_trace().len(), 11);
+
+    // Starting a new run drops the suspended one.
+    vm.set_limits(Limits {
+        max_heap_bytes: Some(1 << 20),
+        ..Limits::default()
+    });
+    // Garbage alone doesn't count against the limit.
+    vm.execute("var s = \"\"; for (var i = 0; i < 3000; i = i + 1) s = s + \"x\";")
+        .unwrap();
+    let error = vm.execute("var s = \"xxxxxxxx\"; while (true) s = s + s;");
+    assert_eq!(error, Err(LoxError::LimitExceeded(Limit::HeapBytes)));
+
+    vm.set_limits(Limits {
+        deadline: Some(Instant::now() + Duration::from_millis(20)),
+        ..Limits::default()
+    });
+    let error = vm.execute("while (true) {}");
+    assert_eq!(error, Err(LoxError::LimitExceeded(Limit::Deadline)));
+}
@@ -0,0 +1,329 @@
+//! Runs every script under tests/lox and checks it against the expectations
+//! written in its comments:
+//!
+//! * `// expect: text` - the next line printed to stdout
+//! * `// expect runtime error: message` - the first stderr line, exiting with 70
+//! * `// expect compile error: message` - a stderr line, exiting with 65
+//!
+//! Each script is also compiled to .loxc and run again from the bytecode, and
+//! run with `-O` to check the optimizer doesn't change its behavior.
+//!
+//! Run it once per value representation: `cargo test` and
+//! `cargo test_nan_boxing`. CI runs both.
+
+use rlox_iii::serialize::{MAGIC, VERSION};
+use rlox_iii::OpCode::*;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    runtime_error: Option<String>,
+    compile_error: Option<String>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expect = Self::default();
+        for line in source.lines() {
+            if let Some(text) = after(line, "// expect: ") {
+                expect.output.push(text);
+            } else if let Some(text) = after(line, "// expect runtime error: ") {
+                expect.runtime_error = Some(text);
+            } else if let Some(text) = after(line, "// expect compile error: ") {
+                expect.compile_error = Some(text);
+            }
+        }
+        expect
+    }
+
+    fn exit_code(&self) -> i32 {
+        if self.compile_error.is_some() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+fn after(line: &str, marker: &str) -> Option<String> {
+    line.find(marker)
+        .map(|at| line[at + marker.len()..].to_string())
+}
+
+fn check(path: &Path, expect: &Expectations) -> Result<(), String> {
+    let out = Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+        .arg(path)
+        .output()
+        .map_err(|e| e.to_string())?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+
+    let output: Vec<&str> = stdout.lines().collect();
+    if output != expect.output {
+        return Err(format!(
+            "expected output {:?}, got {:?}\nstderr: {}",
+            expect.output, output, stderr
+        ));
+    }
+
+    if let Some(message) = &expect.runtime_error {
+        if stderr.lines().next() != Some(message.as_str()) {
+            return Err(format!(
+                "expected runtime error {:?}, got {}",
+                message, stderr
+            ));
+        }
+    }
+    if let Some(message) = &expect.compile_error {
+        if !stderr.lines().any(|line| line == message) {
+            return Err(format!(
+                "expected compile error {:?}, got {}",
+                message, stderr
+            ));
+        }
+    }
+
+    match out.status.code() {
+        Some(code) if code == expect.exit_code() => Ok(()),
+        code => Err(format!(
+            "expected exit code {}, got {:?}\nstderr: {}",
+            expect.exit_code(),
+            code,
+            stderr
+        )),
+    }
+}
+
+/// Compiles `path` to a .loxc file and returns where it was written.
+fn compile(path: &Path) -> Result<PathBuf, String> {
+    compile_with(path, &[], "loxc")
+}
+
+fn compile_with(path: &Path, flags: &[&str], extension: &str) -> Result<PathBuf, String> {
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(path.file_name().unwrap())
+        .with_extension(extension);
+    let status = Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+        .args(flags)
+        .arg("compile")
+        .arg(path)
+        .arg("-o")
+        .arg(&output)
+        .status()
+        .map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("compile failed with {:?}", status.code()));
+    }
+    Ok(output)
+}
+
+fn scripts() -> Vec<(PathBuf, Expectations)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&dir)
+        .expect("tests/lox is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty());
+
+    scripts
+        .into_iter()
+        .map(|path| {
+            let source = fs::read_to_string(&path).unwrap();
+            let expect = Expectations::parse(&source);
+            (path, expect)
+        })
+        .collect()
+}
+
+fn assert_no_failures(failures: Vec<String>) {
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn lox_programs() {
+    let failures = scripts()
+        .iter()
+        .filter_map(|(path, expect)| {
+            check(path, expect)
+                .err()
+                .map(|e| format!("{}: {}", path.display(), e))
+        })
+        .collect();
+    assert_no_failures(failures);
+}
+
+#[test]
+fn lox_programs_from_bytecode() {
+    let failures = scripts()
+        .iter()
+        .filter(|(_, expect)| expect.compile_error.is_none())
+        .filter_map(|(path, expect)| {
+            compile(path)
+                .and_then(|loxc| check(&loxc, expect))
+                .err()
+                .map(|e| format!("{}: {}", path.display(), e))
+        })
+        .collect();
+    assert_no_failures(failures);
+}
+
+/// Every prefix of a valid file, and a file with any single byte changed,
+/// must either run or be rejected with a clean error. A panic exits with 101.
+#[test]
+fn damaged_bytecode_does_not_panic() {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox/classes.lox");
+    let loxc = compile(&source).unwrap();
+    let bytes = fs::read(&loxc).unwrap();
+    let damaged = loxc.with_extension("damaged.loxc");
+
+    let mut variants: Vec<Vec<u8>> = (0..bytes.len()).map(|n| bytes[..n].to_vec()).collect();
+    for at in (0..bytes.len()).step_by(7) {
+        for flip in [0x01, 0x10, 0x80, 0xff] {
+            let mut variant = bytes.clone();
+            variant[at] ^= flip;
+            variants.push(variant);
+        }
+    }
+
+    for variant in variants {
+        fs::write(&damaged, &variant).unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+            .arg(&damaged)
+            .output()
+            .unwrap();
+        assert_ne!(
+            out.status.code(),
+            Some(101),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+}
+
+/// The VM runs the script with no upvalues, so a script claiming one must
+/// be rejected before its OP_GET_UPVALUE reads past them.
+#[test]
+fn scripts_with_upvalues_are_rejected() {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.push(0); // no name
+    bytes.extend_from_slice(&0u32.to_le_bytes()); // arity
+    bytes.extend_from_slice(&1u32.to_le_bytes()); // upvalue_count
+    let code = [
+        OP_GET_UPVALUE as u8,
+        0,
+        OP_POP as u8,
+        OP_NIL as u8,
+        OP_RETURN as u8,
+    ];
+    bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&code);
+    bytes.extend_from_slice(&1u32.to_le_bytes()); // one line run
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&1i32.to_le_bytes());
+    bytes.extend_from_slice(&2u32.to_le_bytes()); // one column run
+    bytes.extend_from_slice(&[code.len() as u8, 1]);
+    bytes.extend_from_slice(&0u32.to_le_bytes()); // no constants
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("upvalue.loxc");
+    fs::write(&path, &bytes).unwrap();
+
+    for flags in [&[][..], &["--disassemble"]] {
+        let out = run(flags, &path);
+        assert_eq!(out.status.code(), Some(65));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("captures upvalues"));
+    }
+}
+
+/// A NaN constant whose payload could pass for a pointer under nan_boxing
+/// must be rejected, not run.
+#[test]
+fn foreign_nan_constants_are_rejected() {
+    let source = Path::new(env!("CARGO_TARGET_TMPDIR")).join("nan.lox");
+    fs::write(&source, "print 1.5;\n").unwrap();
+    let loxc = compile(&source).unwrap();
+    let mut bytes = fs::read(&loxc).unwrap();
+    let number = 1.5f64.to_bits().to_le_bytes();
+    let at = bytes
+        .windows(8)
+        .position(|window| window == number)
+        .unwrap();
+    bytes[at..at + 8].copy_from_slice(&0xfffc_0000_0000_1000u64.to_le_bytes());
+    let damaged = loxc.with_extension("nan.loxc");
+    fs::write(&damaged, &bytes).unwrap();
+
+    let out = run(&[], &damaged);
+    assert_eq!(out.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("non-canonical NaN"));
+}
+
+fn run(flags: &[&str], path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+        .args(flags)
+        .arg(path)
+        .output()
+        .unwrap()
+}
+
+fn same_behavior(expected: &Output, actual: &Output) -> Result<(), String> {
+    if expected.stdout != actual.stdout
+        || expected.stderr != actual.stderr
+        || expected.status.code() != actual.status.code()
+    {
+        return Err(format!(
+            "expected {:?}\n{}{}\ngot {:?}\n{}{}",
+            expected.status.code(),
+            String::from_utf8_lossy(&expected.stdout),
+            String::from_utf8_lossy(&expected.stderr),
+            actual.status.code(),
+            String::from_utf8_lossy(&actual.stdout),
+            String::from_utf8_lossy(&actual.stderr),
+        ));
+    }
+    Ok(())
+}
+
+/// Scripts under tests/lox, plus rlox-II's examples that don't depend on the clock.
+fn example_scripts() -> Vec<PathBuf> {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths: Vec<PathBuf> = scripts().into_iter().map(|(path, _)| path).collect();
+    let examples = manifest.join("../rlox-II/rlox_examples");
+    let mut more: Vec<PathBuf> = fs::read_dir(&examples)
+        .expect("rlox-II/rlox_examples is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !fs::read_to_string(path).unwrap().contains("clock()"))
+        .collect();
+    more.sort();
+    paths.extend(more);
+    paths
+}
+
+/// `-O` must not change a script's output, its errors or their line numbers,
+/// whether it runs from source or from bytecode compiled with `-O`.
+#[test]
+fn optimized_output_matches() {
+    let failures = example_scripts()
+        .iter()
+        .filter_map(|path| {
+            let plain = run(&[], path);
+            same_behavior(&plain, &run(&["-O"], path))
+                .and_then(|_| {
+                    if plain.status.code() == Some(65) {
+                        return Ok(());
+                    }
+                    let loxc = compile_with(path, &["-O"], "opt.loxc")?;
+                    same_behavior(&plain, &run(&[], &loxc))
+                })
+                .err()
+                .map(|e| format!("{}: {}", path.display(), e))
+        })
+        .collect();
+    assert_no_failures(failures);
+}
@@ -0,0 +1,97 @@
+//! Profiles small programs. Instruction counts are exact, so the stacks are
+//! checked as a whole; times only by their shape.
+
+use rlox_iii::profiler::Profiler;
+use rlox_iii::vm::Vm;
+
+use std::process::Command;
+
+fn stacks(profiler: &Profiler) -> String {
+    let mut out = Vec::new();
+    profiler.write_stacks(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn stacks_follow_calls_and_recursion() {
+    let mut vm = Vm::init_vm();
+    vm.set_profiler(Some(Profiler::new()));
+    vm.execute(
+        "fun leaf() { return 1; }
+fun twice() { return leaf() + leaf(); }
+fun down(n) { if (n > 0) down(n - 1); }
+twice();
+down(2);",
+    )
+    .unwrap();
+    let profiler = vm.take_profiler().unwrap();
+    assert_eq!(
+        stacks(&profiler),
+        "script 15
+script;down 14
+script;down;down 14
+script;down;down;down 7
+script;twice 6
+script;twice;leaf 4
+"
+    );
+
+    // Later runs aren't counted once the profiler is taken.
+    vm.execute("twice();").unwrap();
+    assert_eq!(vm.take_profiler().map(|p| stacks(&p)), None);
+}
+
+#[test]
+fn methods_with_one_name_are_told_apart() {
+    let mut vm = Vm::init_vm();
+    vm.set_profiler(Some(Profiler::new()));
+    vm.execute("class A {\n  init() { this.x = 1; }\n}\nclass B {\n  init() {}\n}\nA();\nB();")
+        .unwrap();
+    // Each REPL-style run adds to the same script entry.
+    vm.execute("B();").unwrap();
+    let profiler = vm.take_profiler().unwrap();
+    assert_eq!(
+        stacks(&profiler),
+        "script 25\nscript;init:2 6\nscript;init:5 4\n"
+    );
+}
+
+#[test]
+fn cli_reports_functions_and_lines() {
+    let dir = std::env::temp_dir();
+    let script = dir.join("rlox-profiler.lox");
+    let stacks = dir.join("rlox-profiler.stacks");
+    std::fs::write(
+        &script,
+        "fun square(n) { return n * n; }\nvar total = 0;\nfor (var i = 0; i < 50; i = i + 1) {\n  total = total + square(i);\n}\nprint total;\n",
+    )
+    .unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+        .arg("--profile-stacks")
+        .arg(&stacks)
+        .arg(&script)
+        .output()
+        .unwrap();
+    let stacks_text = std::fs::read_to_string(&stacks).unwrap();
+    std::fs::remove_file(&script).unwrap();
+    std::fs::remove_file(&stacks).unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "40425\n");
+    assert_eq!(stacks_text, "script 814\nscript;square 200\n");
+    let report = String::from_utf8_lossy(&out.stderr);
+    let lines: Vec<&str> = report.lines().collect();
+    assert!(lines[0].starts_with("Profile: 1014 instructions in "));
+    assert!(lines[2].ends_with("function"));
+    let script_row = lines
+        .iter()
+        .find(|line| line.ends_with("  script"))
+        .unwrap();
+    assert!(script_row.trim_start().starts_with("814 "));
+    assert!(script_row.contains(" 100.0  script"));
+    let square_line = lines
+        .iter()
+        .find(|line| line.contains("square  fun square(n) { return n * n; }"))
+        .unwrap();
+    assert!(square_line.trim_start().starts_with("200 "));
+    assert_eq!(out.status.code(), Some(0));
+}
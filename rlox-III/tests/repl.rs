@@ -0,0 +1,56 @@
+//! Drives the REPL through stdin. Prompts are part of stdout: `> ` before
+//! each entry and `... ` before each continuation line.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn repl(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox-iii"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn globals_survive_between_entries() {
+    let out = repl("var a = 1;\nfun f() { return a + 1; }\nprint f();\n");
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "> > > 2\n> \n");
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn bare_expressions_are_printed() {
+    let out = repl("1 + 2\n\"lox\"\n3;\nnil\n");
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "> 3\n> lox\n> > nil\n> \n"
+    );
+}
+
+#[test]
+fn entries_continue_until_balanced() {
+    let out = repl("fun f(n) {\n  return n * 2;\n}\nf(\n  21)\nprint \"a\nb\";\n");
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "> ... ... > ... 42\n> ... a\nb\n> \n"
+    );
+}
+
+#[test]
+fn errors_do_not_end_the_session() {
+    let out = repl("var a = 1;\na = a + nil;\na +;\na\n");
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "> > > > 1\n> \n");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("Operands must be two numbers or two strings."));
+    assert!(stderr.contains("Expect expression."));
+    assert_eq!(out.status.code(), Some(0));
+}